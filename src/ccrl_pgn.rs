@@ -1,18 +1,203 @@
-use crate::ccrllive::CcrlLivePlayer;
-use anyhow::{bail, Result};
+use crate::ccrllive::{CcrlLivePlayer, CcrlLiveRoom};
 use pgn_reader::{BufferedReader, RawComment, RawHeader, SanPlus, Skip, Visitor};
+use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
 
 const SITE_KEY: &str = "Site";
 const WHITE_HEADER_KEY: &str = "White";
 const BLACK_HEADER_KEY: &str = "Black";
 const DATE_HEADER_KEY: &str = "Date";
+const ROUND_HEADER_KEY: &str = "Round";
+const WHITE_ELO_HEADER_KEY: &str = "WhiteElo";
+const BLACK_ELO_HEADER_KEY: &str = "BlackElo";
+const RESULT_HEADER_KEY: &str = "Result";
+const TERMINATION_HEADER_KEY: &str = "Termination";
 const BOOK_MOVE_COMMENT_VALUE: &str = "(Book)";
 
+#[derive(Debug)]
+pub enum PgnError {
+    Read(std::io::Error),
+    Empty,
+    MissingHeader(&'static str),
+    /// A comment which didn't follow a move, e.g. a comment before the first move.
+    OrphanComment(String),
+    /// A move which wasn't followed by the comment CCRL attaches to every move.
+    SanWithoutComment(String),
+}
+
+impl std::fmt::Display for PgnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PgnError::Read(e) => write!(f, "unable to read PGN: {}", e),
+            PgnError::Empty => write!(f, "empty PGN"),
+            PgnError::MissingHeader(key) => write!(f, "missing `{}` header", key),
+            PgnError::OrphanComment(comment) => {
+                write!(f, "comment `{}` without preceding move", comment)
+            }
+            PgnError::SanWithoutComment(san) => write!(f, "move `{}` without comment", san),
+        }
+    }
+}
+
+impl std::error::Error for PgnError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Score {
+    Centipawns(i32),
+    /// Mate in N moves. Negative if the side to move is being mated.
+    Mate(i32),
+}
+
+impl std::ops::Neg for Score {
+    type Output = Score;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            Score::Centipawns(cp) => Score::Centipawns(-cp),
+            Score::Mate(n) => Score::Mate(-n),
+        }
+    }
+}
+
+impl std::fmt::Display for Score {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Score::Centipawns(cp) => {
+                let sign = if *cp < 0 { "-" } else { "" };
+                write!(f, "{}{}.{:02}", sign, cp.abs() / 100, cp.abs() % 100)
+            }
+            Score::Mate(n) if *n < 0 => write!(f, "-M{}", -n),
+            Score::Mate(n) => write!(f, "+M{}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Colour {
+    White,
+    Black,
+}
+
+impl Colour {
+    pub fn opposite(&self) -> Colour {
+        match self {
+            Colour::White => Colour::Black,
+            Colour::Black => Colour::White,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameResult {
+    WhiteWin,
+    BlackWin,
+    Draw,
+    InProgress,
+}
+
+impl GameResult {
+    fn from_header(value: &str) -> Self {
+        match value {
+            "1-0" => GameResult::WhiteWin,
+            "0-1" => GameResult::BlackWin,
+            "1/2-1/2" => GameResult::Draw,
+            _ => GameResult::InProgress,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        *self != GameResult::InProgress
+    }
+}
+
+impl std::fmt::Display for GameResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let result = match self {
+            GameResult::WhiteWin => "1-0",
+            GameResult::BlackWin => "0-1",
+            GameResult::Draw => "1/2-1/2",
+            GameResult::InProgress => "*",
+        };
+
+        write!(f, "{}", result)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PgnMove {
-    notation: String,
-    in_book: bool,
+    pub notation: String,
+    pub in_book: bool,
+
+    /// The principal variation reported by the engine, starting with this move.
+    pub pv: Vec<String>,
+    /// The score from the perspective of the side which played this move.
+    pub score: Option<Score>,
+    pub depth: Option<u32>,
+    /// Time spent on this move in seconds. CCRL reports a unix timestamp here instead for the
+    /// first move out of book.
+    pub time: Option<u64>,
+}
+
+impl PgnMove {
+    fn new(notation: String, comment: &str) -> Self {
+        let mut mv = Self {
+            notation,
+            in_book: comment == BOOK_MOVE_COMMENT_VALUE,
+            pv: vec![],
+            score: None,
+            depth: None,
+            time: None,
+        };
+
+        if mv.in_book {
+            return mv;
+        }
+
+        // Engine moves have comments of the form `(pv...) score/depth time`, where the PV may be
+        // missing for moves with no UCI info (e.g. tablebase moves).
+        let mut rest = comment.trim();
+
+        if let Some(pv_start) = rest.strip_prefix('(') {
+            if let Some((pv, after_pv)) = pv_start.split_once(')') {
+                mv.pv = pv.split_whitespace().map(str::to_string).collect();
+                rest = after_pv;
+            }
+        }
+
+        let mut fields = rest.split_whitespace();
+
+        if let Some((score, depth)) = fields.next().and_then(|f| f.split_once('/')) {
+            mv.score = parse_score(score);
+            mv.depth = depth.parse().ok();
+        }
+
+        mv.time = fields.next().and_then(|t| t.parse().ok());
+
+        mv
+    }
+}
+
+fn parse_score(score: &str) -> Option<Score> {
+    let (negative, unsigned) = match score.as_bytes().first()? {
+        b'-' => (true, &score[1..]),
+        b'+' => (false, &score[1..]),
+        _ => (false, score),
+    };
+
+    if let Some(mate) = unsigned.strip_prefix('M') {
+        let n = mate.parse::<i32>().ok()?;
+        return Some(Score::Mate(if negative { -n } else { n }));
+    }
+
+    let (pawns, fraction) = unsigned.split_once('.').unwrap_or((unsigned, "0"));
+
+    // Normalise the fractional part to exactly two digits of centipawns
+    let fraction = format!("{:0<2}", fraction);
+    let centipawns = pawns.parse::<i32>().ok()? * 100 + fraction.get(..2)?.parse::<i32>().ok()?;
+    let centipawns = if negative { -centipawns } else { centipawns };
+
+    Some(Score::Centipawns(centipawns))
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +206,9 @@ pub struct Pgn {
     pub black_player: CcrlLivePlayer,
     pub date: String,
     pub site: String,
+    pub round: Option<String>,
+    pub result: GameResult,
+    pub termination: Option<String>,
 
     pub moves: Vec<PgnMove>,
 }
@@ -29,19 +217,72 @@ impl Pgn {
     // There's a strange issue where moves later in the game can be reported as 'book' moves.
     // It seems to happen for tablebase moves or other moves with no UCI info.
     // To ensure we get only the opening, stop once we hit the first non-book move.
-    fn opening(&self) -> impl Iterator<Item = &PgnMove> {
+    pub fn opening(&self) -> impl Iterator<Item = &PgnMove> {
         self.moves.iter().take_while(|mv| mv.in_book)
     }
 
+    /// The book moves in move text form, e.g. `1. d4 Nf6 2. c4`
+    pub fn opening_line(&self) -> String {
+        self.opening()
+            .enumerate()
+            .map(|(ply, mv)| {
+                if ply % 2 == 0 {
+                    format!("{}. {}", ply / 2 + 1, mv.notation)
+                } else {
+                    mv.notation.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     /// The game is 'out of book' if any of the moves that were played are not book moves
     pub fn out_of_book(&self) -> bool {
         self.moves.iter().any(|mv| !mv.in_book)
     }
 
+    /// The time the game left book. CCRL reports this as a unix timestamp in place of the move time
+    /// on the first engine move.
+    pub fn start_timestamp(&self) -> Option<u64> {
+        self.moves
+            .iter()
+            .find(|mv| !mv.in_book)
+            .and_then(|mv| mv.time)
+    }
+
+    /// The most recent engine evaluation, from white's perspective
+    pub fn latest_score(&self) -> Option<Score> {
+        // Scores are reported from the perspective of the side which played the move.
+        // White plays the even-numbered plies.
+        self.moves
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(ply, mv)| mv.score.map(|s| if ply % 2 == 0 { s } else { -s }))
+    }
+
     pub fn has_player(&self, player: &str) -> bool {
         self.white_player_is(player) || self.black_player_is(player)
     }
 
+    pub fn player(&self, colour: Colour) -> &CcrlLivePlayer {
+        match colour {
+            Colour::White => &self.white_player,
+            Colour::Black => &self.black_player,
+        }
+    }
+
+    /// The colour `player` is playing as, if they're playing in this game
+    pub fn colour_of(&self, player: &str) -> Option<Colour> {
+        if self.white_player_is(player) {
+            Some(Colour::White)
+        } else if self.black_player_is(player) {
+            Some(Colour::Black)
+        } else {
+            None
+        }
+    }
+
     fn white_player_is(&self, player: &str) -> bool {
         self.white_player.matches(player)
     }
@@ -55,14 +296,39 @@ impl Pgn {
         self.hash(&mut hasher);
         hasher.finish()
     }
+
+    /// Identifies this game as broadcast in a particular room.
+    pub fn game_id(&self, room: &CcrlLiveRoom) -> u64 {
+        let mut hasher = std::hash::DefaultHasher::new();
+        room.code().hash(&mut hasher);
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The hash used to identify games before replays were accounted for: the players, the date,
+    /// and the book. Only needed to read old state files.
+    pub fn legacy_hash(&self) -> u64 {
+        let mut hasher = std::hash::DefaultHasher::new();
+        self.white_player.hash(&mut hasher);
+        self.black_player.hash(&mut hasher);
+        self.date.hash(&mut hasher);
+
+        for mv in self.opening() {
+            mv.notation.hash(&mut hasher);
+        }
+
+        hasher.finish()
+    }
 }
 
-// The hash of a CCRL PGN is the hash of the players, the date, and the book.
-// That is to say, we consider games equivalent if they are played by the same players
-// on the same day, with the same opening book.
-// FIXME: This doesn't account for replays.
+// The hash of a CCRL PGN is the hash of the tournament and round, the players, the date, the book,
+// and the time the game left book.
+// The players, date and book alone aren't enough to tell a game apart from a replay of it,
+// which will have been started at a different time.
 impl Hash for Pgn {
     fn hash<H: Hasher>(&self, state: &mut H) {
+        self.site.hash(state);
+        self.round.hash(state);
         self.white_player.hash(state);
         self.black_player.hash(state);
         self.date.hash(state);
@@ -70,6 +336,8 @@ impl Hash for Pgn {
         for mv in self.opening() {
             mv.notation.hash(state);
         }
+
+        self.start_timestamp().hash(state);
     }
 }
 
@@ -84,12 +352,20 @@ impl Eq for Pgn {}
 struct PgnInfoBuilder {
     pub white_player: Option<String>,
     pub black_player: Option<String>,
+    pub white_elo: Option<u32>,
+    pub black_elo: Option<u32>,
     pub date: Option<String>,
     pub site: Option<String>,
+    pub round: Option<String>,
+    pub result: Option<String>,
+    pub termination: Option<String>,
 
     pub moves: Vec<PgnMove>,
 
     pub last_san: Option<String>,
+
+    /// The first error encountered while visiting the game, which is reported at the end
+    pub error: Option<PgnError>,
 }
 
 impl PgnInfoBuilder {
@@ -97,17 +373,33 @@ impl PgnInfoBuilder {
         Self {
             white_player: None,
             black_player: None,
+            white_elo: None,
+            black_elo: None,
             date: None,
             site: None,
+            round: None,
+            result: None,
+            termination: None,
             moves: vec![],
 
             last_san: None,
+            error: None,
+        }
+    }
+
+    fn set_error(&mut self, error: PgnError) {
+        if self.error.is_none() {
+            self.error = Some(error);
         }
     }
 }
 
+fn required_header(value: &Option<String>, key: &'static str) -> Result<String, PgnError> {
+    value.clone().ok_or(PgnError::MissingHeader(key))
+}
+
 impl Visitor for PgnInfoBuilder {
-    type Result = Pgn;
+    type Result = Result<Pgn, PgnError>;
 
     fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
         let key = String::from_utf8_lossy(key);
@@ -125,29 +417,48 @@ impl Visitor for PgnInfoBuilder {
             self.black_player = Some(value.to_string());
         }
 
+        if key == WHITE_ELO_HEADER_KEY {
+            self.white_elo = value.parse().ok();
+        }
+
+        if key == BLACK_ELO_HEADER_KEY {
+            self.black_elo = value.parse().ok();
+        }
+
         if key == DATE_HEADER_KEY {
             self.date = Some(value.to_string());
         }
+
+        if key == ROUND_HEADER_KEY {
+            self.round = Some(value.to_string());
+        }
+
+        if key == RESULT_HEADER_KEY {
+            self.result = Some(value.to_string());
+        }
+
+        if key == TERMINATION_HEADER_KEY {
+            self.termination = Some(value.to_string());
+        }
     }
 
     fn san(&mut self, san: SanPlus) {
-        assert_eq!(self.last_san, None);
+        if let Some(last_san) = self.last_san.take() {
+            self.set_error(PgnError::SanWithoutComment(last_san));
+        }
 
         self.last_san = Some(san.to_string());
     }
 
     fn comment(&mut self, comment: RawComment<'_>) {
+        let comment = String::from_utf8_lossy(comment.as_bytes()).to_string();
+
         let Some(san) = self.last_san.clone() else {
-            panic!("Saw PGN comment without preceding SAN");
+            self.set_error(PgnError::OrphanComment(comment));
+            return;
         };
 
-        let comment = String::from_utf8_lossy(comment.as_bytes()).to_string();
-        let is_book_move = comment == BOOK_MOVE_COMMENT_VALUE;
-
-        self.moves.push(PgnMove {
-            notation: san,
-            in_book: is_book_move,
-        });
+        self.moves.push(PgnMove::new(san, &comment));
 
         self.last_san = None;
     }
@@ -157,31 +468,50 @@ impl Visitor for PgnInfoBuilder {
     }
 
     fn end_game(&mut self) -> Self::Result {
-        assert_ne!(self.white_player, None);
-        assert_ne!(self.black_player, None);
-        assert_ne!(self.date, None);
-        assert_ne!(self.site, None);
-
-        Pgn {
-            white_player: CcrlLivePlayer::new(&self.white_player.clone().unwrap()),
-            black_player: CcrlLivePlayer::new(&self.black_player.clone().unwrap()),
-            date: self.date.clone().unwrap(),
-            site: self.site.clone().unwrap(),
-            moves: self.moves.clone(),
+        if let Some(last_san) = self.last_san.take() {
+            self.set_error(PgnError::SanWithoutComment(last_san));
         }
+
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        Ok(Pgn {
+            white_player: CcrlLivePlayer::new(&required_header(
+                &self.white_player,
+                WHITE_HEADER_KEY,
+            )?)
+            .with_rating(self.white_elo),
+            black_player: CcrlLivePlayer::new(&required_header(
+                &self.black_player,
+                BLACK_HEADER_KEY,
+            )?)
+            .with_rating(self.black_elo),
+            date: required_header(&self.date, DATE_HEADER_KEY)?,
+            site: required_header(&self.site, SITE_KEY)?,
+            round: self.round.clone(),
+            result: self
+                .result
+                .as_deref()
+                .map_or(GameResult::InProgress, GameResult::from_header),
+            termination: self.termination.clone(),
+            moves: self.moves.clone(),
+        })
     }
 }
 
-pub fn get_pgn_info(pgn: &str) -> Result<Pgn> {
+pub fn get_pgn_info(pgn: &str) -> Result<Pgn, PgnError> {
     let mut reader = BufferedReader::new_cursor(pgn);
 
-    let pgn_info = reader.read_game(&mut PgnInfoBuilder::new())?;
+    let pgn_info = reader
+        .read_game(&mut PgnInfoBuilder::new())
+        .map_err(PgnError::Read)?;
 
     let Some(pgn_info) = pgn_info else {
-        bail!("Empty PGN")
+        return Err(PgnError::Empty);
     };
 
-    Ok(pgn_info)
+    pgn_info
 }
 
 #[cfg(test)]
@@ -216,7 +546,8 @@ mod tests {
 1. d4 {(Book)} Nf6 {(Book)} 2. c4 {(Book)}"#;
 
         let pgn_info = get_pgn_info(sample_pgn).unwrap();
-        assert!(!pgn_info.out_of_book())
+        assert!(!pgn_info.out_of_book());
+        assert_eq!(pgn_info.opening_line(), "1. d4 Nf6 2. c4");
     }
 
     #[test]
@@ -253,4 +584,189 @@ mod tests {
 
         assert_eq!(full_info.as_hash(), shortened_info.as_hash());
     }
+
+    #[test]
+    fn test_pgn_parsing_reads_engine_comments() {
+        let sample_pgn = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+
+1. d4 {(Book)} Nf6 {(Book)} 2. c4 {(Nc3 d5) -0.41/18 1736170414} g6 {(Nf3) 0.57/17 38} 3. Nf3 {1.05/20 12}"#;
+
+        let pgn_info = get_pgn_info(sample_pgn).unwrap();
+
+        let book_move = &pgn_info.moves[0];
+        assert!(book_move.in_book);
+        assert!(book_move.pv.is_empty());
+        assert_eq!(book_move.score, None);
+
+        let first_engine_move = &pgn_info.moves[2];
+        assert!(!first_engine_move.in_book);
+        assert_eq!(first_engine_move.pv, vec!["Nc3", "d5"]);
+        assert_eq!(first_engine_move.score, Some(Score::Centipawns(-41)));
+        assert_eq!(first_engine_move.depth, Some(18));
+        assert_eq!(first_engine_move.time, Some(1736170414));
+
+        let second_engine_move = &pgn_info.moves[3];
+        assert_eq!(second_engine_move.score, Some(Score::Centipawns(57)));
+        assert_eq!(second_engine_move.depth, Some(17));
+        assert_eq!(second_engine_move.time, Some(38));
+
+        let move_without_pv = &pgn_info.moves[4];
+        assert!(move_without_pv.pv.is_empty());
+        assert_eq!(move_without_pv.score, Some(Score::Centipawns(105)));
+        assert_eq!(move_without_pv.depth, Some(20));
+        assert_eq!(move_without_pv.time, Some(12));
+    }
+
+    #[test]
+    fn test_parse_score() {
+        assert_eq!(parse_score("0.00"), Some(Score::Centipawns(0)));
+        assert_eq!(parse_score("-0.05"), Some(Score::Centipawns(-5)));
+        assert_eq!(parse_score("+1.5"), Some(Score::Centipawns(150)));
+        assert_eq!(parse_score("12"), Some(Score::Centipawns(1200)));
+        assert_eq!(parse_score("+M7"), Some(Score::Mate(7)));
+        assert_eq!(parse_score("-M3"), Some(Score::Mate(-3)));
+        assert_eq!(parse_score("garbage"), None);
+        assert_eq!(parse_score(""), None);
+    }
+
+    #[test]
+    fn test_score_display() {
+        assert_eq!(Score::Centipawns(-41).to_string(), "-0.41");
+        assert_eq!(Score::Centipawns(105).to_string(), "1.05");
+        assert_eq!(Score::Mate(4).to_string(), "+M4");
+        assert_eq!(Score::Mate(-2).to_string(), "-M2");
+    }
+
+    #[test]
+    fn test_pgn_parsing_reads_result() {
+        let sample_pgn = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+[Result "0-1"]
+[Termination "adjudication"]
+
+1. d4 {(Book)} Nf6 {(Book)} 2. c4 {(Nc3 d5) -0.41/18 1736170414} g6 {(Nf3) 0.57/17 38} 0-1"#;
+
+        let pgn_info = get_pgn_info(sample_pgn).unwrap();
+
+        assert_eq!(pgn_info.result, GameResult::BlackWin);
+        assert_eq!(pgn_info.termination.as_deref(), Some("adjudication"));
+        assert_eq!(pgn_info.latest_score(), Some(Score::Centipawns(-57)));
+    }
+
+    #[test]
+    fn test_pgn_without_result_is_in_progress() {
+        let sample_pgn = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+[Result "*"]
+
+1. d4 {(Book)} Nf6 {(Book)}"#;
+
+        let pgn_info = get_pgn_info(sample_pgn).unwrap();
+
+        assert_eq!(pgn_info.result, GameResult::InProgress);
+        assert_eq!(pgn_info.latest_score(), None);
+    }
+
+    #[test]
+    fn test_empty_pgn_is_error() {
+        assert!(matches!(get_pgn_info(""), Err(PgnError::Empty)));
+    }
+
+    #[test]
+    fn test_pgn_missing_header_is_error() {
+        let sample_pgn = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+
+1. d4 {(Book)} Nf6 {(Book)}"#;
+
+        assert!(matches!(
+            get_pgn_info(sample_pgn),
+            Err(PgnError::MissingHeader(BLACK_HEADER_KEY))
+        ));
+    }
+
+    #[test]
+    fn test_pgn_comment_without_move_is_error() {
+        let sample_pgn = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+
+{Game start} 1. d4 {(Book)} Nf6 {(Book)}"#;
+
+        let Err(PgnError::OrphanComment(comment)) = get_pgn_info(sample_pgn) else {
+            panic!("Expected orphan comment error");
+        };
+
+        assert_eq!(comment, "Game start");
+    }
+
+    #[test]
+    fn test_pgn_move_without_comment_is_error() {
+        let sample_pgn = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+
+1. d4 Nf6 {(Book)}"#;
+
+        let Err(PgnError::SanWithoutComment(san)) = get_pgn_info(sample_pgn) else {
+            panic!("Expected move without comment error");
+        };
+
+        assert_eq!(san, "d4");
+    }
+
+    #[test]
+    fn test_pgn_last_move_without_comment_is_error() {
+        let sample_pgn = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+
+1. d4 {(Book)} Nf6"#;
+
+        let Err(PgnError::SanWithoutComment(san)) = get_pgn_info(sample_pgn) else {
+            panic!("Expected move without comment error");
+        };
+
+        assert_eq!(san, "Nf6");
+    }
+
+    #[test]
+    fn test_replays_have_different_identity() {
+        let game = r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[Round "1"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+
+1. d4 {(Book)} Nf6 {(Book)} 2. c4 {(Nc3 d5) -0.41/18 1736170414} g6 {(Nf3) 0.57/17 38}"#;
+
+        let replay = game.replace("1736170414", "1736180000");
+        let next_round = game.replace(r#"[Round "1"]"#, r#"[Round "2"]"#);
+
+        let game = get_pgn_info(game).unwrap();
+        let replay = get_pgn_info(&replay).unwrap();
+        let next_round = get_pgn_info(&next_round).unwrap();
+
+        assert_eq!(game.start_timestamp(), Some(1736170414));
+
+        assert_ne!(game.as_hash(), replay.as_hash());
+        assert_ne!(game.as_hash(), next_round.as_hash());
+        assert_eq!(game.legacy_hash(), replay.legacy_hash());
+
+        let room = CcrlLiveRoom::new("1");
+        let other_room = CcrlLiveRoom::new("2");
+        assert_eq!(game.game_id(&room), game.game_id(&room));
+        assert_ne!(game.game_id(&room), game.game_id(&other_room));
+    }
 }
@@ -1,14 +1,20 @@
 use crate::ccrl_pgn;
 use crate::ccrl_pgn::Pgn;
+use crate::http_cache::{CacheStats, Fetched, HttpCache};
 use crate::log::Logger;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Formatter;
 use std::hash::Hasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
-const CCRL_LIVE_ROOMS_URL: &str = "https://ccrl.live/broadcasts";
+pub const DEFAULT_CCRL_LIVE_URL: &str = "https://ccrl.live";
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CcrlLiveRoom {
     code: String,
 }
@@ -26,16 +32,16 @@ impl CcrlLiveRoom {
         format!("https://ccrl.live/{suffix}")
     }
 
+    /// The room on the public site, for linking to
     pub fn url(&self) -> String {
         Self::ccrl_url(&self.code)
     }
-
-    pub fn pgn_url(&self) -> String {
-        Self::ccrl_url(&format!("{}/pgn", self.code))
-    }
 }
 
-#[derive(Debug, Clone, Hash)]
+// The derived hash uses the raw name rather than the normalized one, since game hashes in the
+// state file were produced this way.
+#[allow(clippy::derived_hash_with_manual_eq)]
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 pub struct EngineName(String);
 
 impl EngineName {
@@ -77,20 +83,30 @@ impl std::fmt::Display for EngineName {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CcrlLivePlayer {
     name: EngineName,
+    rating: Option<u32>,
 }
 
 impl CcrlLivePlayer {
     pub fn new(name: &str) -> Self {
         Self {
-            name: EngineName::new(&name),
+            name: EngineName::new(name),
+            rating: None,
         }
     }
 
+    pub fn with_rating(self, rating: Option<u32>) -> Self {
+        Self { rating, ..self }
+    }
+
+    pub fn rating(&self) -> Option<u32> {
+        self.rating
+    }
+
     pub fn matches(&self, name: &str) -> bool {
-        self.name == EngineName::new(&name)
+        self.name == EngineName::new(name)
     }
 }
 
@@ -106,76 +122,195 @@ impl std::hash::Hash for CcrlLivePlayer {
     }
 }
 
-fn get_active_broadcasts() -> Result<Vec<CcrlLiveRoom>> {
-    let response = reqwest::blocking::get(CCRL_LIVE_ROOMS_URL)?.error_for_status()?;
-
-    let rooms = response
-        .json::<Vec<u64>>()?
-        .iter()
-        .map(|r| CcrlLiveRoom::new(&r.to_string()))
-        .collect();
+/// How many rooms to fetch at once
+const MAX_CONCURRENT_FETCHES: usize = 8;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Applies `f` to each item on up to `limit` threads at once, returning the results in order
+fn parallel_map<T: Sync, R: Send>(items: &[T], limit: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+
+    std::thread::scope(|scope| {
+        for _ in 0..limit.min(items.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+
+                let result = f(item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("Every item is mapped"))
+        .collect()
+}
 
-    Ok(rooms)
+/// What's being broadcast at the moment
+pub struct CurrentGames {
+    /// Every room with an active broadcast, including those whose game couldn't be fetched
+    pub rooms: Vec<CcrlLiveRoom>,
+    /// The game in each room, skipping games which are still in book
+    pub games: Vec<(CcrlLiveRoom, Pgn)>,
 }
 
-fn get_current_pgn(room: &CcrlLiveRoom) -> Result<Option<Pgn>> {
-    let client = reqwest::blocking::Client::builder()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
+/// Where we get the games being played from
+pub trait GameSource {
+    fn get_current_games(&self, log: &dyn Logger) -> Result<CurrentGames>;
+}
 
-    let response = client.get(room.pgn_url()).send()?.error_for_status()?;
+/// Parses a room's PGN, logging if it can't be parsed
+pub fn parse_room_pgn(room: &CcrlLiveRoom, pgn: &str, log: &dyn Logger) -> Option<Pgn> {
+    match ccrl_pgn::get_pgn_info(pgn) {
+        Ok(pgn) => Some(pgn),
+        Err(e) => {
+            log.warning(&format!(
+                "Unable to parse PGN for room {}: {}",
+                room.code(),
+                e
+            ));
 
-    if response.status() != reqwest::StatusCode::OK {
-        return Ok(None);
+            None
+        }
     }
-
-    let pgn_content = response.text()?;
-
-    let pgn_info = ccrl_pgn::get_pgn_info(&pgn_content)?;
-
-    Ok(Some(pgn_info))
 }
 
-pub fn get_current_games(log: &dyn Logger) -> Result<Vec<(CcrlLiveRoom, Pgn)>> {
-    let mut pgns: Vec<(CcrlLiveRoom, Pgn)> = vec![];
+/// Fetches games from ccrl.live, sharing one pool of connections between polls. Rooms are only
+/// downloaded and parsed again when they've changed.
+pub struct CcrlLiveClient {
+    client: reqwest::blocking::Client,
+    /// e.g. `https://ccrl.live`
+    base_url: String,
+    cache: HttpCache,
+    /// The last game parsed from each room
+    games: Mutex<HashMap<String, Pgn>>,
+}
 
-    let broadcasts = get_active_broadcasts()?;
+impl CcrlLiveClient {
+    pub fn new(base_url: &str) -> Result<Self> {
+        let client = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cache: HttpCache::new(),
+            games: Mutex::new(HashMap::new()),
+        })
+    }
 
-    for room in &broadcasts {
-        let pgn_fetch_result = get_current_pgn(room);
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
 
-        let Ok(pgn) = pgn_fetch_result else {
-            let e = pgn_fetch_result.unwrap_err();
+    fn broadcasts_url(&self) -> String {
+        format!("{}/broadcasts", self.base_url)
+    }
 
-            log.warning(&format!(
-                "Unable to fetch PGN for room {}: {:?}",
-                room.code(),
-                e
-            ));
+    fn pgn_url(&self, room: &CcrlLiveRoom) -> String {
+        format!("{}/{}/pgn", self.base_url, room.code())
+    }
 
-            continue;
+    fn get_active_broadcasts(&self) -> Result<Vec<CcrlLiveRoom>> {
+        let Some(response) = self.cache.get(&self.client, &self.broadcasts_url())? else {
+            bail!("No active broadcasts in response");
         };
 
-        // We may have no PGN for the room if there's no active broadcast
-        let Some(pgn) = pgn else {
-            continue;
-        };
+        let rooms = serde_json::from_str::<Vec<u64>>(&response.body)?
+            .iter()
+            .map(|r| CcrlLiveRoom::new(&r.to_string()))
+            .collect();
 
-        // Don't consider games which are still in book to have started since we need all the book
-        // moves so we can hash the game correctly
-        if !pgn.out_of_book() {
-            continue;
-        }
+        Ok(rooms)
+    }
 
-        pgns.push((room.clone(), pgn));
+    fn get_current_pgn(&self, room: &CcrlLiveRoom) -> Result<Option<Fetched>> {
+        self.cache.get(&self.client, &self.pgn_url(room))
     }
+}
+
+impl GameSource for CcrlLiveClient {
+    /// Fetches the game in each active room. Rooms are fetched in parallel, so a slow room only
+    /// delays the poll by as long as it takes itself.
+    fn get_current_games(&self, log: &dyn Logger) -> Result<CurrentGames> {
+        let mut pgns: Vec<(CcrlLiveRoom, Pgn)> = vec![];
+
+        let broadcasts = self.get_active_broadcasts()?;
+
+        // Forget rooms which have closed
+        let codes = broadcasts.iter().map(|r| r.code()).collect::<HashSet<_>>();
+        let pgn_urls = broadcasts
+            .iter()
+            .map(|r| self.pgn_url(r))
+            .collect::<HashSet<_>>();
+        let mut games = self.games.lock().unwrap();
+        games.retain(|code, _| codes.contains(code));
+        let broadcasts_url = self.broadcasts_url();
+        self.cache
+            .retain(|url| url == broadcasts_url || pgn_urls.contains(url));
+
+        let pgn_fetch_results = parallel_map(&broadcasts, MAX_CONCURRENT_FETCHES, |room| {
+            self.get_current_pgn(room)
+        });
+
+        for (room, pgn_fetch_result) in broadcasts.iter().zip(pgn_fetch_results) {
+            let pgn = match pgn_fetch_result {
+                Ok(pgn) => pgn,
+                Err(e) => {
+                    log.warning(&format!(
+                        "Unable to fetch PGN for room {}: {:?}",
+                        room.code(),
+                        e
+                    ));
+
+                    continue;
+                }
+            };
+
+            // We may have no PGN for the room if there's no active broadcast
+            let Some(pgn) = pgn else {
+                continue;
+            };
+
+            let cached = games.get(&room.code()).filter(|_| !pgn.modified).cloned();
+
+            let Some(pgn) = cached.or_else(|| parse_room_pgn(room, &pgn.body, log)) else {
+                continue;
+            };
+            games.insert(room.code(), pgn.clone());
+
+            // Don't consider games which are still in book to have started since we need all the
+            // book moves so we can hash the game correctly
+            if !pgn.out_of_book() {
+                continue;
+            }
+
+            pgns.push((room.clone(), pgn));
+        }
 
-    Ok(pgns)
+        Ok(CurrentGames {
+            rooms: broadcasts,
+            games: pgns,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::log::StdoutLogger;
+    use crate::test_server::TestServer;
 
     #[test]
     fn test_matches_does_not_match_substring() {
@@ -196,4 +331,58 @@ mod tests {
     fn test_matches_ignores_date_version() {
         assert!(CcrlLivePlayer::new("Colossus 2025b").matches("Colossus"));
     }
+
+    #[test]
+    fn test_parallel_map_keeps_order_and_limit() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        let items = (0..20).collect::<Vec<u64>>();
+
+        let results = parallel_map(&items, 4, |i| {
+            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now_running, Ordering::SeqCst);
+
+            std::thread::sleep(Duration::from_millis(10 * (i % 3)));
+
+            running.fetch_sub(1, Ordering::SeqCst);
+            i * 2
+        });
+
+        assert_eq!(results, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+        assert!(max_running.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[test]
+    fn test_parallel_map_with_no_items() {
+        assert!(parallel_map(&[] as &[u64], 4, |i| *i).is_empty());
+    }
+
+    #[test]
+    fn test_live_client_uses_base_url() {
+        let server = TestServer::start(vec![
+            (200, "[7]"),
+            (
+                200,
+                r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "Lunar"]
+[Black "Betsabe_II 2023"]
+[Result "*"]
+
+1. d4 {(Book)} Nf6 {(Nc3 d5) -0.41/18 1736170414}"#,
+            ),
+        ]);
+
+        let client = CcrlLiveClient::new(&format!("{}/", server.url)).unwrap();
+        let games = client.get_current_games(&StdoutLogger).unwrap().games;
+
+        assert_eq!(server.request().path, "/broadcasts");
+        assert_eq!(server.request().path, "/7/pgn");
+
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].0.code(), "7");
+        assert_eq!(games[0].0.url(), "https://ccrl.live/7");
+        assert!(games[0].1.white_player.matches("Lunar"));
+    }
 }
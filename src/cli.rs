@@ -0,0 +1,270 @@
+use crate::ccrl_pgn::Pgn;
+use crate::ccrllive::{CcrlLiveClient, CcrlLivePlayer, CcrlLiveRoom, GameSource};
+use crate::config::ConfigArgs;
+use crate::fixture::FixtureSource;
+use crate::log::StdoutLogger;
+use crate::state::SeenGames;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+
+/// Get notified when games featuring your engine start on ccrl.live broadcasts
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Poll for games and send notifications until stopped (the default)
+    Run,
+    /// Poll for games once, send what's due, then exit
+    Once,
+    /// Check a config file for mistakes
+    ValidateConfig {
+        /// A path or URL, defaulting to the configured config URL
+        location: Option<String>,
+    },
+    /// List the rooms with a game in progress
+    ListRooms,
+    /// Show the game in progress in a room
+    ShowGame {
+        /// The room code, e.g. `1`
+        room: String,
+    },
+    /// Inspect or change which games have been seen
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StateCommand {
+    /// Print every seen game as a line of JSON
+    Dump,
+    /// Forget a game so it's notified again if it's seen
+    Forget {
+        /// The game's ID from `state dump` or `show-game`, or a legacy hash
+        game_id: u64,
+    },
+}
+
+/// Where games come from, ignoring everything else in the config
+fn game_source(args: &ConfigArgs) -> Result<Box<dyn GameSource>> {
+    Ok(match &args.replay_dir {
+        Some(dir) => Box::new(FixtureSource::load(dir)?),
+        None => Box::new(CcrlLiveClient::new(&args.live_url)?),
+    })
+}
+
+pub fn list_rooms(args: &ConfigArgs) -> Result<()> {
+    for (room, game) in game_source(args)?.get_current_games(&StdoutLogger)?.games {
+        println!(
+            "{}\t{} vs {}\t{}\t{} plies\t{}",
+            room.code(),
+            game.white_player,
+            game.black_player,
+            game.site,
+            game.moves.len(),
+            room.url()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn show_game(args: &ConfigArgs, code: &str) -> Result<()> {
+    let games = game_source(args)?.get_current_games(&StdoutLogger)?.games;
+
+    let Some((room, game)) = games.iter().find(|(room, _)| room.code() == code) else {
+        bail!("No game out of book in room {}", code);
+    };
+
+    print!("{}", describe_game(room, game));
+
+    Ok(())
+}
+
+fn describe_game(room: &CcrlLiveRoom, game: &Pgn) -> String {
+    let player = |player: &CcrlLivePlayer| match player.rating() {
+        Some(rating) => format!("{} ({})", player, rating),
+        None => player.to_string(),
+    };
+
+    let mut description = format!("Room: {} ({})\n", room.code(), room.url());
+    description += &format!("Tournament: {}\n", game.site);
+    if let Some(round) = &game.round {
+        description += &format!("Round: {}\n", round);
+    }
+    description += &format!("Date: {}\n", game.date);
+    description += &format!("White: {}\n", player(&game.white_player));
+    description += &format!("Black: {}\n", player(&game.black_player));
+    description += &format!("Opening: {}\n", game.opening_line());
+    description += &format!("Plies: {}\n", game.moves.len());
+    if let Some(score) = game.latest_score() {
+        description += &format!("Eval: {}\n", score);
+    }
+    description += &format!("Result: {}\n", game.result);
+    if let Some(termination) = &game.termination {
+        description += &format!("Termination: {}\n", termination);
+    }
+    description += &format!("Game ID: {}\n", game.game_id(room));
+
+    description
+}
+
+/// Inspects or changes the state without taking the lock, so it can be used while the notifier is
+/// running
+pub fn state(args: &ConfigArgs, command: StateCommand) -> Result<()> {
+    match command {
+        StateCommand::Dump => {
+            let seen_games = SeenGames::open_read_only(&args.state_dir)?;
+
+            for game in seen_games.games()? {
+                println!("{}", serde_json::to_string(&game)?);
+            }
+        }
+        StateCommand::Forget { game_id } => {
+            let mut seen_games = SeenGames::open_read_write(&args.state_dir)?;
+
+            if !seen_games.forget(game_id)? {
+                bail!("Game {} isn't in the state", game_id);
+            }
+
+            println!("Forgot game {}", game_id);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_flags_override_env_vars() {
+        // Flags are global, so can be given after the subcommand
+        let cli = Cli::try_parse_from([
+            "ccrl-live-notifier",
+            "state",
+            "forget",
+            "123",
+            "--state-dir",
+            "/var/lib/ccrl",
+            "--live-url",
+            "http://localhost:8080",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.config.state_dir.to_str(), Some("/var/lib/ccrl"));
+        assert_eq!(cli.config.live_url, "http://localhost:8080");
+        assert!(matches!(
+            cli.command,
+            Some(Command::State {
+                command: StateCommand::Forget { game_id: 123 }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_state_retention_must_be_sensible() {
+        let retention = |days: &str| {
+            let cli = Cli::try_parse_from([
+                "ccrl-live-notifier",
+                "--config-url",
+                "config.json5",
+                "--state-retention-days",
+                days,
+            ])
+            .unwrap();
+
+            crate::config::get_config(&cli.config).map(|config| config.state_retention)
+        };
+
+        assert_eq!(
+            retention("2").unwrap(),
+            std::time::Duration::from_secs(2 * 24 * 60 * 60)
+        );
+        assert!(retention("0").is_err());
+        assert!(retention(&u64::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_partly_configured_matrix_is_error() {
+        let cli = Cli::try_parse_from([
+            "ccrl-live-notifier",
+            "--config-url",
+            "config.json5",
+            "--matrix-homeserver",
+            "https://matrix.example.org",
+            "--matrix-room-id",
+            "!room:example.org",
+        ])
+        .unwrap();
+
+        let error = crate::config::get_config(&cli.config)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The Matrix notifier is only partly configured, set CCRL_MATRIX_ACCESS_TOKEN too"
+        );
+    }
+
+    #[test]
+    fn test_partly_configured_email_is_error() {
+        let cli = Cli::try_parse_from([
+            "ccrl-live-notifier",
+            "--config-url",
+            "config.json5",
+            "--smtp-url",
+            "smtp://localhost",
+        ])
+        .unwrap();
+
+        let error = crate::config::get_config(&cli.config)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The email notifier is only partly configured, set CCRL_SMTP_FROM too"
+        );
+    }
+
+    #[test]
+    fn test_describe_game() {
+        let game = crate::ccrl_pgn::get_pgn_info(
+            r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[Round "3.1"]
+[White "Lunar"]
+[Black "Betsabe_II 2023"]
+[WhiteElo "2900"]
+[Result "*"]
+
+1. d4 {(Book)} Nf6 {(Nc3 d5) -0.41/18 1736170414}"#,
+        )
+        .unwrap();
+        let room = CcrlLiveRoom::new("1");
+
+        let description = describe_game(&room, &game);
+
+        assert!(description.starts_with("Room: 1 (https://ccrl.live/1)\n"));
+        assert!(description.contains("Round: 3.1\n"));
+        assert!(description.contains("White: Lunar (2900)\n"));
+        assert!(description.contains("Eval: 0.41\n"));
+        assert!(description.contains(&format!("Game ID: {}\n", game.game_id(&room))));
+    }
+}
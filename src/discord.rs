@@ -1,25 +1,350 @@
-use anyhow::Result;
+use crate::notify::{CheckResponse, Notifier, NotifyContent, RateLimit};
+use anyhow::{bail, Result};
+use reqwest::Url;
 use serde_json::{json, Value};
 
-pub fn send_message(webhook_url: &str, message: &str) -> Result<()> {
+// Embed colours for subscribed engines, picked by hashing the engine name so each engine keeps
+// the same colour across notifications.
+const ENGINE_COLOURS: [u32; 8] = [
+    0x5865F2, 0x57F287, 0xFEE75C, 0xEB459E, 0xED4245, 0x3498DB, 0xE67E22, 0x1ABC9C,
+];
+const DEFAULT_COLOUR: u32 = 0x95A5A6;
+
+pub struct DiscordNotifier {
+    webhook_url: String,
+    rate_limit: RateLimit,
+}
+
+impl DiscordNotifier {
+    pub fn new(webhook_url: String) -> Self {
+        Self {
+            webhook_url,
+            rate_limit: RateLimit::default(),
+        }
+    }
+}
+
+impl Notifier for DiscordNotifier {
+    fn send_message(&self, message: &str) -> Result<()> {
+        send_message(&self.webhook_url, &self.rate_limit, message)
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        send_embed(
+            &self.webhook_url,
+            &self.rate_limit,
+            &mentions_str(content),
+            progress_embed(content),
+        )
+        .map(Some)
+    }
+
+    fn notify_progress(&self, notification_id: &str, content: &NotifyContent) -> Result<()> {
+        edit_embed(
+            &self.webhook_url,
+            &self.rate_limit,
+            notification_id,
+            progress_embed(content),
+        )
+    }
+
+    fn notify_finished(
+        &self,
+        notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        match notification_id {
+            Some(message_id) => edit_embed(
+                &self.webhook_url,
+                &self.rate_limit,
+                message_id,
+                finished_embed(content),
+            ),
+            None => send_embed(
+                &self.webhook_url,
+                &self.rate_limit,
+                &mentions_str(content),
+                finished_embed(content),
+            )
+            .map(|_| ()),
+        }
+    }
+}
+
+fn mentions_str(content: &NotifyContent) -> String {
+    content
+        .sorted_mentions()
+        .iter()
+        .map(|m| format!("<@!{}>", m))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn colour(content: &NotifyContent) -> u32 {
+    let Some(engine) = content.engines.first() else {
+        return DEFAULT_COLOUR;
+    };
+
+    // FNV-1a, since we need a hash which is stable between runs
+    let hash = engine
+        .to_ascii_lowercase()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+
+    ENGINE_COLOURS[(hash % ENGINE_COLOURS.len() as u64) as usize]
+}
+
+fn embed(content: &NotifyContent) -> Value {
+    let mut fields = vec![
+        json!({ "name": "White", "value": format!("`{}`", content.white_player), "inline": true }),
+        json!({ "name": "Black", "value": format!("`{}`", content.black_player), "inline": true }),
+        json!({ "name": "Tournament", "value": content.tournament, "inline": false }),
+        json!({
+            "name": "Room",
+            "value": format!("[{}]({})", content.room.code(), content.room.url()),
+            "inline": true
+        }),
+    ];
+
+    if !content.opening.is_empty() {
+        fields.push(json!({ "name": "Opening", "value": content.opening, "inline": false }));
+    }
+
+    json!({
+        "title": format!("{} vs. {}", content.white_player, content.black_player),
+        "url": content.room.url(),
+        "color": colour(content),
+        "fields": fields,
+    })
+}
+
+fn progress_embed(content: &NotifyContent) -> Value {
+    let mut embed = embed(content);
+
+    let fields = embed["fields"].as_array_mut().unwrap();
+    fields.push(
+        json!({ "name": "Move", "value": content.move_number().to_string(), "inline": true }),
+    );
+
+    if let Some(score) = content.score {
+        fields.push(json!({ "name": "Eval", "value": format!("`{}`", score), "inline": true }));
+    }
+
+    embed
+}
+
+fn finished_embed(content: &NotifyContent) -> Value {
+    let mut embed = embed(content);
+
+    let fields = embed["fields"].as_array_mut().unwrap();
+    fields.push(json!({ "name": "Result", "value": content.result_description(), "inline": true }));
+    fields.push(json!({ "name": "Plies", "value": content.plies.to_string(), "inline": true }));
+
+    if let Some(score) = content.score {
+        fields
+            .push(json!({ "name": "Final eval", "value": format!("`{}`", score), "inline": true }));
+    }
+
+    embed["title"] = json!(format!(
+        "{} vs. {} finished",
+        content.white_player, content.black_player
+    ));
+
+    embed
+}
+
+fn send_message(webhook_url: &str, rate_limit: &RateLimit, message: &str) -> Result<()> {
     call_webhook(
         webhook_url,
+        rate_limit,
         json!({
             "username": "ccrl-live-notifier",
             "allowed_mentions": { "parse": ["users"] },
             "content": message
         }),
-    )
+    )?;
+
+    Ok(())
 }
 
-fn call_webhook(webhook_url: &str, body: Value) -> Result<()> {
+/// Sends a message with an embed, returning the ID of the message so it can be edited later
+fn send_embed(
+    webhook_url: &str,
+    rate_limit: &RateLimit,
+    message: &str,
+    embed: Value,
+) -> Result<String> {
+    let response = call_webhook(
+        webhook_url,
+        rate_limit,
+        json!({
+            "username": "ccrl-live-notifier",
+            "allowed_mentions": { "parse": ["users"] },
+            "content": message,
+            "embeds": [embed]
+        }),
+    )?;
+
+    let Some(message_id) = response["id"].as_str() else {
+        bail!("No message ID in webhook response: {}", response);
+    };
+
+    Ok(message_id.to_string())
+}
+
+/// Replaces the embed of a message previously sent with `send_embed`
+fn edit_embed(
+    webhook_url: &str,
+    rate_limit: &RateLimit,
+    message_id: &str,
+    embed: Value,
+) -> Result<()> {
+    let mut url = Url::parse(webhook_url)?;
+
+    let Ok(mut path) = url.path_segments_mut() else {
+        bail!("Invalid webhook URL: {}", webhook_url);
+    };
+    path.push("messages").push(message_id);
+    drop(path);
+
+    rate_limit.check()?;
+
     let client = reqwest::blocking::Client::new();
 
-    client
+    let response = client
+        .patch(url)
+        .json(&json!({ "embeds": [embed] }))
+        .send()?;
+
+    rate_limit.update(&response);
+    response.check_status()?;
+
+    Ok(())
+}
+
+fn call_webhook(webhook_url: &str, rate_limit: &RateLimit, body: Value) -> Result<Value> {
+    rate_limit.check()?;
+
+    let client = reqwest::blocking::Client::new();
+
+    // With `wait`, Discord responds with the message that was created
+    let response = client
         .post(webhook_url)
+        .query(&[("wait", "true")])
         .json(&body)
-        .send()?
-        .error_for_status()?;
+        .send()?;
 
-    Ok(())
+    rate_limit.update(&response);
+
+    Ok(response.check_status()?.json()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::notify::RateLimited;
+    use crate::test_server::TestServer;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mentions() {
+        assert_eq!(mentions_str(&content(&["2", "1"], &[])), "<@!1> <@!2>");
+    }
+
+    #[test]
+    fn test_embed_fields() {
+        let embed = embed(&content(&[], &["RookieMonster"]));
+
+        let fields = embed["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| (f["name"].as_str().unwrap(), f["value"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            fields,
+            vec![
+                ("White", "`RookieMonster 1.9.9 64-bit`"),
+                ("Black", "`Betsabe_II 2023`"),
+                ("Tournament", "114th Amateur D11"),
+                ("Room", "[1](https://ccrl.live/1)"),
+                ("Opening", "1. d4 Nf6"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_progress_embed_has_move_and_eval() {
+        let embed = progress_embed(&content(&[], &["RookieMonster"]));
+
+        let fields = embed["fields"].as_array().unwrap();
+        assert!(fields
+            .iter()
+            .any(|f| f["name"] == "Move" && f["value"] == "2"));
+        assert!(fields
+            .iter()
+            .any(|f| f["name"] == "Eval" && f["value"] == "`-0.41`"));
+    }
+
+    #[test]
+    fn test_finished_embed_has_result() {
+        let embed = finished_embed(&content(&[], &["RookieMonster"]));
+
+        let fields = embed["fields"].as_array().unwrap();
+        assert!(fields
+            .iter()
+            .any(|f| f["name"] == "Result" && f["value"] == "1-0"));
+    }
+
+    #[test]
+    fn test_colour_depends_on_engine() {
+        assert_eq!(colour(&content(&[], &[])), DEFAULT_COLOUR);
+        assert_eq!(
+            colour(&content(&[], &["Lunar"])),
+            colour(&content(&[], &["Lunar", "Stockfish"]))
+        );
+    }
+
+    #[test]
+    fn test_429_is_rate_limited() {
+        let server = TestServer::start_with_headers(vec![(
+            429,
+            vec![("Retry-After", "2.5")],
+            r#"{ "retry_after": 2.5 }"#,
+        )]);
+
+        let error = DiscordNotifier::new(server.url.clone())
+            .notify(&content(&["1"], &[]))
+            .unwrap_err();
+
+        assert_eq!(
+            error.downcast_ref::<RateLimited>().unwrap().retry_after,
+            Duration::from_millis(2500)
+        );
+    }
+
+    #[test]
+    fn test_exhausted_rate_limit_holds_back_requests() {
+        let server = TestServer::start_with_headers(vec![(
+            200,
+            vec![
+                ("X-RateLimit-Remaining", "0"),
+                ("X-RateLimit-Reset-After", "60"),
+            ],
+            r#"{ "id": "123" }"#,
+        )]);
+
+        let notifier = DiscordNotifier::new(server.url.clone());
+        let content = content(&["1"], &[]);
+
+        assert_eq!(notifier.notify(&content).unwrap().as_deref(), Some("123"));
+
+        // The bucket is empty, so the next request isn't sent
+        let error = notifier.notify(&content).unwrap_err();
+        assert!(error.downcast_ref::<RateLimited>().unwrap().retry_after > Duration::from_secs(59));
+    }
 }
@@ -0,0 +1,578 @@
+use crate::config::EmailConfig;
+use crate::notify::{InvalidRecipient, Notifier, NotifyContent, PartiallySent};
+use crate::outbox::{backoff, MAX_ATTEMPTS};
+use crate::state::write_atomically;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, MessageBuilder};
+use lettre::{Message, SmtpTransport, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where digests are kept until they're sent, in the state directory
+const DIGEST_FILE: &str = "email-digest.json";
+
+struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Mailer {
+    fn new(config: &EmailConfig) -> Result<Self> {
+        Ok(Self {
+            transport: SmtpTransport::from_url(&config.smtp_url)?.build(),
+            from: config.from.parse()?,
+        })
+    }
+
+    fn builder(&self, subject: String) -> MessageBuilder {
+        Message::builder().from(self.from.clone()).subject(subject)
+    }
+
+    fn send(&self, builder: MessageBuilder, body: String) -> Result<()> {
+        let message = builder.header(ContentType::TEXT_PLAIN).body(body)?;
+
+        self.transport.send(&message)?;
+
+        Ok(())
+    }
+}
+
+/// The recipients' addresses, skipping invalid ones. The error for those is returned separately,
+/// to give once the others have been mailed.
+fn valid_addresses(content: &NotifyContent) -> Result<(Vec<&str>, Option<anyhow::Error>)> {
+    let mut addresses = vec![];
+    let mut invalid = HashSet::new();
+    let mut first_error = None;
+
+    for recipient in content.sorted_recipients() {
+        let address = recipient.user_id()?;
+
+        match address.parse::<Mailbox>() {
+            Ok(_) => addresses.push(address),
+            Err(e) => {
+                invalid.insert(recipient.clone());
+                first_error.get_or_insert(InvalidRecipient(format!("`{}`: {}", address, e)));
+            }
+        }
+    }
+
+    let error = first_error.map(|error| {
+        if addresses.is_empty() {
+            error.into()
+        } else {
+            PartiallySent {
+                failed: invalid,
+                notification_id: None,
+                error: error.into(),
+            }
+            .into()
+        }
+    });
+
+    Ok((addresses, error))
+}
+
+fn subject(content: &NotifyContent) -> String {
+    format!(
+        "{} vs. {} ({})",
+        content.white_player, content.black_player, content.tournament
+    )
+}
+
+fn game_summary(content: &NotifyContent) -> String {
+    format!(
+        "{} vs. {}\nTournament: {}\nRoom: {}",
+        content.white_player,
+        content.black_player,
+        content.tournament,
+        content.room.url()
+    )
+}
+
+fn result_summary(content: &NotifyContent) -> String {
+    let mut summary = format!(
+        "Result: {} after {} plies",
+        content.result_description(),
+        content.plies
+    );
+
+    if let Some(score) = content.score {
+        summary += &format!(", final eval {}", score);
+    }
+
+    summary
+}
+
+/// Mails users as soon as a game starts, and again with its result. Recipients are Bcc'd so they
+/// can't see each other's addresses.
+pub struct EmailNotifier {
+    mailer: Mailer,
+    message_counter: AtomicU64,
+}
+
+impl EmailNotifier {
+    pub fn new(config: &EmailConfig) -> Result<Self> {
+        Ok(Self {
+            mailer: Mailer::new(config)?,
+            message_counter: AtomicU64::new(0),
+        })
+    }
+
+    fn message_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let domain = self.mailer.from.email.domain();
+
+        format!(
+            "<{}.{}@{}>",
+            nanos,
+            self.message_counter.fetch_add(1, Ordering::Relaxed),
+            domain
+        )
+    }
+
+    fn send_to_recipients(
+        &self,
+        content: &NotifyContent,
+        mut builder: MessageBuilder,
+        body: String,
+    ) -> Result<()> {
+        let (addresses, invalid) = valid_addresses(content)?;
+
+        if !addresses.is_empty() {
+            for address in addresses {
+                builder = builder.bcc(address.parse()?);
+            }
+
+            self.mailer.send(builder, body)?;
+        }
+
+        invalid.map_or(Ok(()), Err)
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn send_message(&self, _message: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        let message_id = self.message_id();
+
+        let builder = self
+            .mailer
+            .builder(subject(content))
+            .message_id(Some(message_id.clone()));
+
+        self.send_to_recipients(
+            content,
+            builder,
+            format!("A game has started.\n\n{}\n", game_summary(content)),
+        )
+        .map_err(|e| PartiallySent::with_notification_id(e, Some(message_id.clone())))?;
+
+        Ok(Some(message_id))
+    }
+
+    fn notify_finished(
+        &self,
+        notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        let mut builder = self
+            .mailer
+            .builder(format!("Re: {}", subject(content)))
+            .message_id(Some(self.message_id()));
+
+        // Reply to the mail for the start of the game, so they're threaded together
+        if let Some(notification_id) = notification_id {
+            builder = builder
+                .in_reply_to(notification_id.to_string())
+                .references(notification_id.to_string());
+        }
+
+        self.send_to_recipients(
+            content,
+            builder,
+            format!("{}\n\n{}\n", game_summary(content), result_summary(content)),
+        )
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Digest {
+    /// The games which have finished on each day (UTC) for each address
+    games: BTreeMap<String, BTreeMap<NaiveDate, Vec<String>>>,
+    /// Addresses whose last digest failed to send
+    retries: BTreeMap<String, Retry>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Retry {
+    attempts: u32,
+    /// Unix time after which to try again
+    next_attempt: i64,
+}
+
+/// Collects the results of each user's games, and mails them a digest once a day (UTC). The
+/// digest is saved in the state directory, so games collected before a restart are still sent.
+pub struct EmailDigestNotifier {
+    mailer: Mailer,
+    path: PathBuf,
+    digest: Mutex<Digest>,
+}
+
+impl EmailDigestNotifier {
+    pub fn new(config: &EmailConfig, state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(DIGEST_FILE);
+
+        let digest = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Digest::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            mailer: Mailer::new(config)?,
+            path,
+            digest: Mutex::new(digest),
+        })
+    }
+
+    fn save(&self, digest: &Digest) -> Result<()> {
+        write_atomically(&self.path, serde_json::to_string(digest)?.as_bytes())
+    }
+
+    fn add_on(&self, today: NaiveDate, content: &NotifyContent) -> Result<()> {
+        let game = format!("{}\n{}", game_summary(content), result_summary(content));
+        let (addresses, invalid) = valid_addresses(content)?;
+
+        let mut digest = self.digest.lock().unwrap();
+        let mut updated = digest.clone();
+
+        for address in addresses {
+            updated
+                .games
+                .entry(address.to_string())
+                .or_default()
+                .entry(today)
+                .or_default()
+                .push(game.clone());
+        }
+
+        // Only kept once it's saved, so retrying after an error doesn't add the game twice
+        self.save(&updated)?;
+        *digest = updated;
+
+        invalid.map_or(Ok(()), Err)
+    }
+
+    fn send_digest(&self, address: &str, date: NaiveDate, games: &[String]) -> Result<()> {
+        let builder = self
+            .mailer
+            .builder(format!("CCRL games for {}", date))
+            .to(address.parse()?);
+
+        self.mailer.send(builder, games.join("\n\n") + "\n")
+    }
+
+    /// Sends a digest for each day before `now` which has games. Each user's digests are sent
+    /// separately, and those which fail are retried with backoff, like the outbox, until they're
+    /// given up on.
+    fn flush_at(&self, now: DateTime<Utc>) -> Result<()> {
+        let today = now.date_naive();
+        let mut digest = self.digest.lock().unwrap();
+        let Digest { games, retries } = &mut *digest;
+        let mut changed = false;
+        let mut first_error = None;
+
+        for (address, days) in games.iter_mut() {
+            if retries
+                .get(address)
+                .is_some_and(|retry| retry.next_attempt > now.timestamp())
+            {
+                continue;
+            }
+
+            let due: Vec<NaiveDate> = days.range(..today).map(|(date, _)| *date).collect();
+
+            for date in due {
+                changed = true;
+
+                match self.send_digest(address, date, &days[&date]) {
+                    Ok(()) => {
+                        days.remove(&date);
+                        retries.remove(address);
+                    }
+                    Err(e) => {
+                        let retry = retries.entry(address.clone()).or_default();
+                        retry.attempts += 1;
+
+                        if retry.attempts >= MAX_ATTEMPTS {
+                            days.retain(|date, _| *date >= today);
+                            retries.remove(address);
+                            first_error.get_or_insert(
+                                e.context(format!("Giving up on the digest for {}", address)),
+                            );
+                        } else {
+                            retry.next_attempt =
+                                now.timestamp() + backoff(retry.attempts).as_secs() as i64;
+                            first_error.get_or_insert(e);
+                        }
+
+                        break;
+                    }
+                }
+            }
+        }
+
+        if !changed {
+            return Ok(());
+        }
+
+        games.retain(|_, days| !days.is_empty());
+
+        let saved = self.save(&digest);
+
+        match first_error {
+            Some(e) => Err(e),
+            None => saved,
+        }
+    }
+}
+
+impl Notifier for EmailDigestNotifier {
+    fn send_message(&self, _message: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn notify(&self, _content: &NotifyContent) -> Result<Option<String>> {
+        // Only results go in the digest
+        Ok(None)
+    }
+
+    fn notify_finished(
+        &self,
+        _notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        self.add_on(Utc::now().date_naive(), content)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.flush_at(Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::test_server::SmtpSink;
+
+    fn config(sink: &SmtpSink) -> EmailConfig {
+        EmailConfig {
+            smtp_url: sink.url.clone(),
+            from: "CCRL Live <ccrl@example.com>".to_string(),
+        }
+    }
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ccrl-email-{}-{}", name, std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn test_immediate_mails_are_threaded() {
+        let sink = SmtpSink::start();
+        let notifier = EmailNotifier::new(&config(&sink)).unwrap();
+        let content = content(&["b@example.com", "a@example.com"], &[]);
+
+        let message_id = notifier.notify(&content).unwrap().unwrap();
+
+        let mail = sink.mail();
+        assert_eq!(mail.from, "<ccrl@example.com>");
+        assert_eq!(mail.to, vec!["<a@example.com>", "<b@example.com>"]);
+        assert!(mail
+            .data
+            .contains("Subject: RookieMonster 1.9.9 64-bit vs. Betsabe_II 2023"));
+        assert!(mail.data.contains(&format!("Message-ID: {}", message_id)));
+        assert!(!mail.data.contains("a@example.com"));
+
+        notifier
+            .notify_finished(Some(&message_id), &content)
+            .unwrap();
+
+        let mail = sink.mail();
+        assert!(mail.data.contains(&format!("In-Reply-To: {}", message_id)));
+        assert!(mail
+            .data
+            .contains("Result: 1-0 after 3 plies, final eval -0.41"));
+    }
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, day).unwrap()
+    }
+
+    fn midday(day: u32) -> DateTime<Utc> {
+        self::day(day).and_hms_opt(12, 0, 0).unwrap().and_utc()
+    }
+
+    #[test]
+    fn test_digest_is_sent_once_the_day_is_over() {
+        let sink = SmtpSink::start();
+        let dir = state_dir("digest");
+        let notifier = EmailDigestNotifier::new(&config(&sink), &dir).unwrap();
+
+        notifier
+            .add_on(day(1), &content(&["a@example.com", "b@example.com"], &[]))
+            .unwrap();
+        notifier
+            .add_on(day(1), &content(&["a@example.com"], &[]))
+            .unwrap();
+
+        notifier.flush_at(midday(1)).unwrap();
+
+        notifier.flush_at(midday(2)).unwrap();
+
+        let mail = sink.mail();
+        assert_eq!(mail.to, vec!["<a@example.com>"]);
+        assert!(mail
+            .data
+            .contains(&format!("Subject: CCRL games for {}", day(1))));
+        assert_eq!(mail.data.matches("Result: 1-0").count(), 2);
+
+        let mail = sink.mail();
+        assert_eq!(mail.to, vec!["<b@example.com>"]);
+        assert_eq!(mail.data.matches("Result: 1-0").count(), 1);
+
+        assert!(notifier.digest.lock().unwrap().games.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_digest_is_sent_for_each_day() {
+        let sink = SmtpSink::start();
+        let dir = state_dir("days");
+        let notifier = EmailDigestNotifier::new(&config(&sink), &dir).unwrap();
+
+        notifier
+            .add_on(day(1), &content(&["a@example.com"], &[]))
+            .unwrap();
+        notifier
+            .add_on(day(2), &content(&["a@example.com"], &[]))
+            .unwrap();
+        notifier
+            .add_on(day(3), &content(&["a@example.com"], &[]))
+            .unwrap();
+
+        notifier.flush_at(midday(3)).unwrap();
+
+        for date in [day(1), day(2)] {
+            let mail = sink.mail();
+            assert!(mail
+                .data
+                .contains(&format!("Subject: CCRL games for {}", date)));
+            assert_eq!(mail.data.matches("Result: 1-0").count(), 1);
+        }
+
+        let digest = notifier.digest.lock().unwrap();
+        let days: Vec<_> = digest.games["a@example.com"].keys().copied().collect();
+        assert_eq!(days, vec![day(3)]);
+        drop(digest);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_digest_survives_restart() {
+        let sink = SmtpSink::start();
+        let dir = state_dir("restart");
+
+        let notifier = EmailDigestNotifier::new(&config(&sink), &dir).unwrap();
+        notifier
+            .add_on(day(1), &content(&["a@example.com"], &[]))
+            .unwrap();
+        drop(notifier);
+
+        let notifier = EmailDigestNotifier::new(&config(&sink), &dir).unwrap();
+        notifier.flush_at(midday(2)).unwrap();
+
+        assert_eq!(sink.mail().to, vec!["<a@example.com>"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failing_digest_is_given_up_on() {
+        let dir = state_dir("give-up");
+        let config = EmailConfig {
+            // Nothing listens on the discard port, so every send fails
+            smtp_url: "smtp://127.0.0.1:9".to_string(),
+            from: "CCRL Live <ccrl@example.com>".to_string(),
+        };
+        let notifier = EmailDigestNotifier::new(&config, &dir).unwrap();
+
+        notifier
+            .add_on(day(1), &content(&["a@example.com"], &[]))
+            .unwrap();
+        notifier
+            .add_on(day(2), &content(&["a@example.com"], &[]))
+            .unwrap();
+
+        let mut now = midday(2);
+        notifier.flush_at(now).unwrap_err();
+
+        // Not retried until the backoff is over
+        notifier.flush_at(now).unwrap();
+        assert_eq!(
+            notifier.digest.lock().unwrap().retries["a@example.com"].attempts,
+            1
+        );
+
+        for _ in 1..MAX_ATTEMPTS - 1 {
+            now += chrono::Duration::hours(1);
+            notifier.flush_at(now).unwrap_err();
+        }
+
+        now += chrono::Duration::hours(1);
+        let error = notifier.flush_at(now).unwrap_err();
+        assert!(format!("{:#}", error).contains("Giving up on the digest for a@example.com"));
+
+        // Only the day which was due is dropped
+        let digest = notifier.digest.lock().unwrap();
+        let days: Vec<_> = digest.games["a@example.com"].keys().copied().collect();
+        assert_eq!(days, vec![day(2)]);
+        assert!(digest.retries.is_empty());
+        drop(digest);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_address_is_skipped() {
+        let sink = SmtpSink::start();
+        let notifier = EmailNotifier::new(&config(&sink)).unwrap();
+
+        let error = notifier
+            .notify(&content(&["not an address", "a@example.com"], &[]))
+            .unwrap_err();
+
+        assert_eq!(sink.mail().to, vec!["<a@example.com>"]);
+
+        let partial = error.downcast_ref::<PartiallySent>().unwrap();
+        assert!(partial.error.is::<InvalidRecipient>());
+        assert!(partial.notification_id.is_some());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::ccrllive::{parse_room_pgn, CcrlLiveRoom, CurrentGames, GameSource};
+use crate::log::Logger;
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Replays a recording of ccrl.live, for testing without the real site.
+///
+/// The recording is a directory with a numbered directory for each poll, e.g. `0`, `1`, `2`. Each
+/// of those has `broadcasts.json`, the list of active rooms, and `<room>.pgn` for each room which
+/// had a game. Every poll moves on to the next directory, and the last one is repeated once the
+/// recording runs out.
+pub struct FixtureSource {
+    steps: Vec<PathBuf>,
+    next_step: AtomicUsize,
+}
+
+impl FixtureSource {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut steps = vec![];
+
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            let step = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<u32>().ok());
+
+            if let (Some(step), true) = (step, path.is_dir()) {
+                steps.push((step, path));
+            }
+        }
+
+        if steps.is_empty() {
+            bail!("No recorded polls in {}", dir.display());
+        }
+
+        steps.sort();
+
+        Ok(Self {
+            steps: steps.into_iter().map(|(_, path)| path).collect(),
+            next_step: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl GameSource for FixtureSource {
+    fn get_current_games(&self, log: &dyn Logger) -> Result<CurrentGames> {
+        let step = self.next_step.fetch_add(1, Ordering::Relaxed);
+        let dir = &self.steps[step.min(self.steps.len() - 1)];
+
+        let broadcasts = std::fs::read_to_string(dir.join("broadcasts.json"))?;
+
+        let mut rooms = vec![];
+        let mut games = vec![];
+
+        for code in serde_json::from_str::<Vec<u64>>(&broadcasts)? {
+            let room = CcrlLiveRoom::new(&code.to_string());
+            rooms.push(room.clone());
+
+            // Rooms without a PGN have no active broadcast
+            let Ok(pgn) = std::fs::read_to_string(dir.join(format!("{}.pgn", code))) else {
+                continue;
+            };
+
+            let Some(pgn) = parse_room_pgn(&room, &pgn, log) else {
+                continue;
+            };
+
+            if pgn.out_of_book() {
+                games.push((room, pgn));
+            }
+        }
+
+        Ok(CurrentGames { rooms, games })
+    }
+}
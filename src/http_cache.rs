@@ -0,0 +1,197 @@
+use anyhow::{bail, Result};
+use reqwest::blocking::Client;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub requests: u64,
+    pub not_modified: u64,
+    /// The size of the bodies we didn't have to download again
+    pub bytes_saved: u64,
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} requests not modified, {} bytes saved",
+            self.not_modified, self.requests, self.bytes_saved
+        )
+    }
+}
+
+struct CacheEntry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+pub struct Fetched {
+    pub body: String,
+    /// Whether the body has changed since it was last fetched
+    pub modified: bool,
+}
+
+/// Remembers the `ETag` and `Last-Modified` validators of responses, so URLs which are fetched
+/// repeatedly are only downloaded again when they've changed
+#[derive(Default)]
+pub struct HttpCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    stats: Mutex<CacheStats>,
+}
+
+impl HttpCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Fetches `url`, returning the cached body if the server says it hasn't changed. Returns
+    /// `None` for successful responses without a body, e.g. redirects when the client doesn't
+    /// follow them.
+    pub fn get(&self, client: &Client, url: &str) -> Result<Option<Fetched>> {
+        let mut request = client.get(url);
+
+        if let Some(entry) = self.entries.lock().unwrap().get(url) {
+            if let Some(ref etag) = entry.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(ref last_modified) = entry.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send()?.error_for_status()?;
+
+        self.stats.lock().unwrap().requests += 1;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            let entries = self.entries.lock().unwrap();
+
+            let Some(entry) = entries.get(url) else {
+                bail!("Not modified response for {}, which isn't cached", url);
+            };
+
+            let mut stats = self.stats.lock().unwrap();
+            stats.not_modified += 1;
+            stats.bytes_saved += entry.body.len() as u64;
+
+            return Ok(Some(Fetched {
+                body: entry.body.clone(),
+                modified: false,
+            }));
+        }
+
+        if response.status() != StatusCode::OK {
+            return Ok(None);
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+
+        let body = response.text()?;
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if etag.is_some() || last_modified.is_some() {
+            entries.insert(
+                url.to_string(),
+                CacheEntry {
+                    etag,
+                    last_modified,
+                    body: body.clone(),
+                },
+            );
+        } else {
+            entries.remove(url);
+        }
+
+        Ok(Some(Fetched {
+            body,
+            modified: true,
+        }))
+    }
+
+    /// Forgets URLs which aren't wanted any more
+    pub fn retain(&self, mut keep: impl FnMut(&str) -> bool) {
+        self.entries.lock().unwrap().retain(|url, _| keep(url));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::TestServer;
+
+    #[test]
+    fn test_validators_are_sent_and_not_modified_uses_cache() {
+        let server = TestServer::start_with_headers(vec![
+            (
+                200,
+                vec![
+                    ("ETag", "\"v1\""),
+                    ("Last-Modified", "Mon, 06 Jan 2025 13:00:00 GMT"),
+                ],
+                "[1, 2]",
+            ),
+            (304, vec![], ""),
+        ]);
+
+        let cache = HttpCache::new();
+        let client = Client::new();
+        let url = format!("{}/broadcasts", server.url);
+
+        let fetched = cache.get(&client, &url).unwrap().unwrap();
+        assert!(fetched.modified);
+        assert_eq!(server.request().header("if-none-match"), None);
+
+        let fetched = cache.get(&client, &url).unwrap().unwrap();
+        assert!(!fetched.modified);
+        assert_eq!(fetched.body, "[1, 2]");
+
+        let request = server.request();
+        assert_eq!(request.header("if-none-match"), Some("\"v1\""));
+        assert_eq!(
+            request.header("if-modified-since"),
+            Some("Mon, 06 Jan 2025 13:00:00 GMT")
+        );
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                requests: 2,
+                not_modified: 1,
+                bytes_saved: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn test_responses_without_validators_are_not_cached() {
+        let server = TestServer::start(vec![(200, "first"), (200, "second")]);
+
+        let cache = HttpCache::new();
+        let client = Client::new();
+
+        cache.get(&client, &server.url).unwrap();
+        server.request();
+
+        let fetched = cache.get(&client, &server.url).unwrap().unwrap();
+        assert!(fetched.modified);
+        assert_eq!(fetched.body, "second");
+        assert_eq!(server.request().header("if-none-match"), None);
+    }
+}
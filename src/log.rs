@@ -1,5 +1,7 @@
 use crate::config::Config;
-use crate::{discord, log};
+use crate::discord::DiscordNotifier;
+use crate::log;
+use crate::notify::Notifier;
 use std::panic::PanicHookInfo;
 
 pub fn get_logger(config: &Config) -> Box<dyn Logger> {
@@ -71,41 +73,41 @@ impl Logger for StdoutLogger {
     }
 }
 
-#[derive(Clone)]
 pub struct DiscordLogger {
-    log_webhook: String,
+    notifier: DiscordNotifier,
 }
 
 impl DiscordLogger {
     pub fn new(log_webhook: String) -> DiscordLogger {
-        Self { log_webhook }
+        Self {
+            notifier: DiscordNotifier::new(log_webhook),
+        }
     }
 }
 
 impl Logger for DiscordLogger {
     fn start(&self) {
-        let _ = discord::send_message(&self.log_webhook, "```───────────────────────────────────────────────────────────────────────────────────────────────────────────```");
+        let _ = self.notifier.send_message("```───────────────────────────────────────────────────────────────────────────────────────────────────────────```");
     }
 
     fn info(&self, msg: &str) {
         println!("{}", msg);
 
-        let _ = discord::send_message(&self.log_webhook, msg);
+        let _ = self.notifier.send_message(msg);
     }
 
     fn warning(&self, msg: &str) {
         println!(":yellow_circle: {}", msg);
 
-        let _ = discord::send_message(&self.log_webhook, msg);
+        let _ = self.notifier.send_message(msg);
     }
 
     fn error(&self, msg: &str) {
         eprintln!("{}", msg);
 
-        let _ = discord::send_message(
-            &self.log_webhook,
-            &("<@!106120945231466496> :red_circle:".to_string() + msg),
-        );
+        let _ = self
+            .notifier
+            .send_message(&("<@!106120945231466496> :red_circle:".to_string() + msg));
     }
 
     fn panic(&self, info: &PanicHookInfo) {
@@ -113,9 +115,8 @@ impl Logger for DiscordLogger {
 
         eprintln!("{}", msg);
 
-        let _ = discord::send_message(
-            &self.log_webhook,
-            &("<@!106120945231466496> :fire: :fire: :fire: ".to_string() + &msg),
-        );
+        let _ = self
+            .notifier
+            .send_message(&("<@!106120945231466496> :fire: :fire: :fire: ".to_string() + &msg));
     }
 }
@@ -1,46 +1,108 @@
+use crate::ccrllive::{CcrlLiveClient, GameSource};
+use crate::cli::{Cli, Command};
+use crate::config::{ConfigArgs, NotifyConfigLoader};
+use crate::fixture::FixtureSource;
 use crate::log::Logger;
-use crate::notify::NotifyContent;
-use crate::state::SeenGames;
-use anyhow::Result;
-use std::collections::HashSet;
-use std::time::Duration;
+use crate::outbox::Outbox;
+use crate::poller::Poller;
+use crate::state::{SeenGames, StateLock};
+use anyhow::{bail, Result};
+use clap::Parser;
+use std::time::{Duration, Instant};
 
 mod ccrl_pgn;
 mod ccrllive;
+mod cli;
 mod config;
 mod discord;
+mod email;
+mod fixture;
+mod http_cache;
 mod log;
+mod matrix;
 mod notify;
+mod outbox;
+mod poller;
+mod push;
+mod slack;
 mod state;
+mod telegram;
+#[cfg(test)]
+mod test_server;
+mod tracker;
+mod validate;
+mod webhook;
 
 const POLL_DELAY: Duration = Duration::from_secs(30);
+/// How often to log how much conditional fetching has saved
+const CACHE_STATS_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 fn main() -> Result<()> {
-    let config = config::get_config().expect("Unable to load config");
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(&cli.config, false),
+        Command::Once => run(&cli.config, true),
+        Command::ValidateConfig { location } => {
+            let Some(location) = location.or(cli.config.config_url) else {
+                bail!("No config to validate, give a path or URL or set CCRL_CONFIG_URL");
+            };
+
+            let valid = validate::validate_config_source(&location)?;
+            std::process::exit(if valid { 0 } else { 1 });
+        }
+        Command::ListRooms => cli::list_rooms(&cli.config),
+        Command::ShowGame { room } => cli::show_game(&cli.config, &room),
+        Command::State { command } => cli::state(&cli.config, command),
+    }
+}
+
+/// Polls for games and notifies, forever or just once
+fn run(args: &ConfigArgs, once: bool) -> Result<()> {
+    let config = config::get_config(args)?;
+    // Held until we exit. Taken before anything else so a second instance exits quietly.
+    let _state_lock = StateLock::acquire(&config.state_dir)?;
     let log = log::get_logger(&config);
 
-    std::panic::set_hook(Box::new(|info| {
+    let panic_args = args.clone();
+    std::panic::set_hook(Box::new(move |info| {
         // FIXME: Lifetimes mean we need to re-do this initialisation in the panic handler.
-        let config = config::get_config().unwrap();
+        let config = config::get_config(&panic_args).unwrap();
         let log = log::get_logger(&config);
         log.panic(info);
     }));
 
     log.start();
 
-    let mut first_run = true;
-
-    let mut seen_games = SeenGames::load().expect("Unable to load state");
-    let mut notify_config = config::get_notify_config(&config).expect("Unable to load config");
+    let ccrl_live = CcrlLiveClient::new(&config.ccrl_live_url)?;
+    let replay = config
+        .replay_dir
+        .as_deref()
+        .map(FixtureSource::load)
+        .transpose()?;
+    let source: &dyn GameSource = match replay {
+        Some(ref replay) => replay,
+        None => &ccrl_live,
+    };
+    let mut cache_stats_logged_at = Instant::now();
+    let notifiers = notify::get_notifiers(&config)?;
+
+    let seen_games = SeenGames::load(&config.state_dir, &log)
+        .expect("Unable to load state")
+        .with_retention(config.state_retention);
+    let outbox = Outbox::load(&config.state_dir).expect("Unable to load outbox");
+    let mut poller = Poller::new(source, &notifiers, &log, seen_games, outbox);
+
+    let mut notify_config_loader = NotifyConfigLoader::new(config.config_source.clone());
+    let mut notify_config = notify_config_loader.load().expect("Unable to load config");
 
     log.info(&format!("Loaded config: {:?}", notify_config));
 
     loop {
-        let new_notify_config = config::get_notify_config(&config);
+        let new_notify_config = notify_config_loader.load_if_changed();
         if let Err(e) = new_notify_config {
             log.warning(&format!("Unable to fetch new config: {:?}", e));
-        } else {
-            let new_notify_config = new_notify_config?;
+        } else if let Some(new_notify_config) = new_notify_config? {
             if notify_config != new_notify_config {
                 log.info(&format!(
                     "<@!106120945231466496> Config update loaded: {:?}",
@@ -50,90 +112,19 @@ fn main() -> Result<()> {
             }
         }
 
-        let current_games_result = ccrllive::get_current_games(&log);
-
-        let Ok(current_games) = current_games_result else {
-            let e = current_games_result.unwrap_err();
-
-            log.warning(&format!("Unable to fetch in-progress games: {:?}", e));
-
-            std::thread::sleep(POLL_DELAY);
-            continue;
-        };
-
-        if first_run {
-            for (room, game) in &current_games {
-                log.info(&format!(
-                    "`{}` In progress: `{}` vs `{}` ({} plies)",
-                    room.code(),
-                    game.white_player,
-                    game.black_player,
-                    game.moves.len()
-                ))
-            }
-
-            first_run = false;
-        }
-
-        let new_games = current_games
-            .iter()
-            // Filter out games we've already seen.
-            .filter(|(_, game)| !seen_games.contains(game))
-            .collect::<Vec<_>>();
-
-        for (room, game) in &new_games {
+        if cache_stats_logged_at.elapsed() >= CACHE_STATS_INTERVAL {
             log.info(&format!(
-                "`{}` - `{}` vs `{}`",
-                room.code(),
-                game.white_player,
-                game.black_player,
+                "HTTP cache: ccrl.live {}; config {}",
+                ccrl_live.cache_stats(),
+                notify_config_loader.cache_stats()
             ));
+            cache_stats_logged_at = Instant::now();
+        }
 
-            let mut mentions = HashSet::new();
-
-            for (engine, user_configs) in &notify_config.engines {
-                if game.has_player(engine) {
-                    // Filter users based on tournament rules
-                    let matching_users: Vec<String> = user_configs
-                        .iter()
-                        .filter(|user_config| user_config.rules.notify_for_tournament(&game.site))
-                        .map(|user_config| user_config.user_id.clone())
-                        .collect();
-
-                    if !matching_users.is_empty() {
-                        mentions.extend(matching_users.iter().cloned());
-                        log.info(&format!(
-                            "`{}` Will notify {} users for engine `{}`",
-                            room.code(),
-                            matching_users.len(),
-                            &engine,
-                        ));
-                    }
-                }
-            }
-
-            if !mentions.is_empty() {
-                let notify_result = notify::notify(
-                    &config,
-                    NotifyContent {
-                        white_player: game.white_player.clone(),
-                        black_player: game.black_player.clone(),
-                        tournament: game.site.clone(),
-                        room: room.clone(),
-                        mentions,
-                    },
-                );
-
-                if let Err(e) = notify_result {
-                    log.error(&format!("Unable to send notify: {:?}", e));
-                }
-            }
-
-            let write_state_result = seen_games.add(game);
+        poller.poll(&notify_config);
 
-            if let Err(e) = write_state_result {
-                log.error(&format!("Unable to write seen game to file: {:?}", e));
-            }
+        if once {
+            return Ok(());
         }
 
         std::thread::sleep(POLL_DELAY);
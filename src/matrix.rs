@@ -0,0 +1,286 @@
+use crate::config::MatrixConfig;
+use crate::notify::{html_escape, CheckResponse, Notifier, NotifyContent};
+use anyhow::{bail, Result};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Posts notifications to a Matrix room through the client-server API
+pub struct MatrixNotifier {
+    config: MatrixConfig,
+    /// Transaction IDs must be unique per access token, so they're based on the time we started
+    /// and a counter
+    txn_prefix: u128,
+    txn_counter: AtomicU64,
+}
+
+impl MatrixNotifier {
+    pub fn new(config: MatrixConfig) -> Self {
+        let txn_prefix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+
+        Self {
+            config,
+            txn_prefix,
+            txn_counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Sends an `m.room.message` event, returning its event ID
+    fn send_event(&self, content: Value) -> Result<String> {
+        let txn_id = format!(
+            "{}-{}",
+            self.txn_prefix,
+            self.txn_counter.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let mut url = Url::parse(&self.config.homeserver)?;
+
+        let Ok(mut path) = url.path_segments_mut() else {
+            bail!("Invalid homeserver URL: {}", self.config.homeserver);
+        };
+        path.pop_if_empty().extend([
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &self.config.room_id,
+            "send",
+            "m.room.message",
+            &txn_id,
+        ]);
+        drop(path);
+
+        let client = reqwest::blocking::Client::new();
+
+        let response = client
+            .put(url)
+            .bearer_auth(&self.config.access_token)
+            .json(&content)
+            .send()?
+            .check_status()?
+            .json::<Value>()?;
+
+        let Some(event_id) = response["event_id"].as_str() else {
+            bail!("No event ID in Matrix response: {}", response);
+        };
+
+        Ok(event_id.to_string())
+    }
+
+    /// Replaces a message we sent earlier with new content
+    fn edit_event(&self, event_id: &str, new_content: Value) -> Result<()> {
+        let mut content = new_content.clone();
+
+        // Clients which don't support edits show the fallback body, marked as an edit
+        content["body"] = json!(format!("* {}", new_content["body"].as_str().unwrap_or("")));
+        content["formatted_body"] = json!(format!(
+            "* {}",
+            new_content["formatted_body"].as_str().unwrap_or("")
+        ));
+        // Users were mentioned by the original message, so the edit shouldn't ping them again
+        content["m.mentions"] = json!({});
+        content["m.new_content"] = new_content;
+        content["m.relates_to"] = json!({ "rel_type": "m.replace", "event_id": event_id });
+
+        self.send_event(content)?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for MatrixNotifier {
+    fn send_message(&self, message: &str) -> Result<()> {
+        self.send_event(json!({ "msgtype": "m.text", "body": message }))?;
+
+        Ok(())
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        self.send_event(progress_message(content)).map(Some)
+    }
+
+    fn notify_progress(&self, notification_id: &str, content: &NotifyContent) -> Result<()> {
+        self.edit_event(notification_id, progress_message(content))
+    }
+
+    fn notify_finished(
+        &self,
+        notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        match notification_id {
+            Some(event_id) => self.edit_event(event_id, finished_message(content)),
+            None => self.send_event(finished_message(content)).map(|_| ()),
+        }
+    }
+}
+
+fn message(content: &NotifyContent, details: &[String]) -> Value {
+    let mentions = content.sorted_mentions();
+
+    let mut body = format!(
+        "{} - {} ({}): {} vs. {}",
+        content.room.code(),
+        content.tournament,
+        content.room.url(),
+        content.white_player,
+        content.black_player,
+    );
+
+    let mut formatted_body = format!(
+        "<a href=\"{}\">{} - {}</a>: <code>{}</code> vs. <code>{}</code>",
+        html_escape(&content.room.url()),
+        html_escape(&content.room.code()),
+        html_escape(&content.tournament),
+        html_escape(&content.white_player.to_string()),
+        html_escape(&content.black_player.to_string()),
+    );
+
+    if !details.is_empty() {
+        body += &format!(" ({})", details.join(", "));
+        formatted_body += &format!(" ({})", html_escape(&details.join(", ")));
+    }
+
+    if !mentions.is_empty() {
+        body += &format!("   cc. {}", mentions.join(" "));
+        formatted_body += &format!(
+            "   cc. {}",
+            mentions
+                .iter()
+                .map(|m| format!(
+                    "<a href=\"https://matrix.to/#/{}\">{}</a>",
+                    html_escape(m),
+                    html_escape(m)
+                ))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+
+    json!({
+        "msgtype": "m.text",
+        "body": body,
+        "format": "org.matrix.custom.html",
+        "formatted_body": formatted_body,
+        "m.mentions": { "user_ids": mentions },
+    })
+}
+
+fn progress_message(content: &NotifyContent) -> Value {
+    let mut details = vec![format!("move {}", content.move_number())];
+
+    if let Some(score) = content.score {
+        details.push(format!("eval {}", score));
+    }
+
+    message(content, &details)
+}
+
+fn finished_message(content: &NotifyContent) -> Value {
+    let mut details = vec![
+        format!("finished {}", content.result_description()),
+        format!("{} plies", content.plies),
+    ];
+
+    if let Some(score) = content.score {
+        details.push(format!("final eval {}", score));
+    }
+
+    message(content, &details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::test_server::TestServer;
+
+    fn notifier(server: &TestServer) -> MatrixNotifier {
+        MatrixNotifier::new(MatrixConfig {
+            homeserver: server.url.clone(),
+            access_token: "token".to_string(),
+            room_id: "!room:example.org".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_notify_sends_message_with_mentions() {
+        let server = TestServer::start(vec![(200, r#"{ "event_id": "$event" }"#)]);
+
+        let event_id = notifier(&server)
+            .notify(&content(&["@b:example.org", "@a:example.org"], &[]))
+            .unwrap();
+        assert_eq!(event_id.as_deref(), Some("$event"));
+
+        let request = server.request();
+        assert_eq!(request.method, "PUT");
+        assert!(request
+            .path
+            .starts_with("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/"));
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+
+        let body = request.json();
+        assert_eq!(
+            body["m.mentions"]["user_ids"],
+            json!(["@a:example.org", "@b:example.org"])
+        );
+        assert!(body["formatted_body"]
+            .as_str()
+            .unwrap()
+            .contains("<a href=\"https://matrix.to/#/@a:example.org\">@a:example.org</a>"));
+    }
+
+    #[test]
+    fn test_notify_finished_edits_message() {
+        let server = TestServer::start(vec![(200, r#"{ "event_id": "$edit" }"#)]);
+
+        notifier(&server)
+            .notify_finished(Some("$event"), &content(&["@a:example.org"], &[]))
+            .unwrap();
+
+        let body = server.request().json();
+        assert_eq!(
+            body["m.relates_to"],
+            json!({ "rel_type": "m.replace", "event_id": "$event" })
+        );
+        assert!(body["m.new_content"]["body"]
+            .as_str()
+            .unwrap()
+            .contains("finished 1-0"));
+        assert!(body["body"].as_str().unwrap().starts_with("* "));
+        assert!(body["formatted_body"].as_str().unwrap().starts_with("* "));
+    }
+
+    #[test]
+    fn test_edit_does_not_mention_users_again() {
+        let server = TestServer::start(vec![(200, r#"{ "event_id": "$edit" }"#)]);
+
+        notifier(&server)
+            .notify_progress("$event", &content(&["@a:example.org"], &[]))
+            .unwrap();
+
+        let body = server.request().json();
+        assert_eq!(body["m.mentions"], json!({}));
+        assert_eq!(
+            body["m.new_content"]["m.mentions"]["user_ids"],
+            json!(["@a:example.org"])
+        );
+    }
+
+    #[test]
+    fn test_transaction_ids_are_unique() {
+        let server = TestServer::start(vec![
+            (200, r#"{ "event_id": "$1" }"#),
+            (200, r#"{ "event_id": "$2" }"#),
+        ]);
+        let notifier = notifier(&server);
+
+        notifier.send_message("one").unwrap();
+        notifier.send_message("two").unwrap();
+
+        assert_ne!(server.request().path, server.request().path);
+    }
+}
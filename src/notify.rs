@@ -1,41 +1,500 @@
+use crate::ccrl_pgn::{GameResult, Pgn, Score};
 use crate::ccrllive::{CcrlLivePlayer, CcrlLiveRoom};
 use crate::config::Config;
-use crate::discord;
-use anyhow::Result;
-use std::collections::HashSet;
+use crate::discord::DiscordNotifier;
+use crate::email::{EmailDigestNotifier, EmailNotifier};
+use crate::matrix::MatrixNotifier;
+use crate::push::{GotifyNotifier, NtfyNotifier, PushTarget};
+use crate::slack::SlackNotifier;
+use crate::telegram::TelegramNotifier;
+use crate::webhook::{WebhookNotifier, WebhookTarget};
+use anyhow::{bail, Result};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+/// The notification backends users can choose between
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    Discord,
+    Slack,
+    Matrix,
+    Telegram,
+    Ntfy,
+    Gotify,
+    Email,
+    EmailDigest,
+    Webhook,
+}
+
+impl std::fmt::Display for NotifierKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NotifierKind::Discord => "discord",
+            NotifierKind::Slack => "slack",
+            NotifierKind::Matrix => "matrix",
+            NotifierKind::Telegram => "telegram",
+            NotifierKind::Ntfy => "ntfy",
+            NotifierKind::Gotify => "gotify",
+            NotifierKind::Email => "email",
+            NotifierKind::EmailDigest => "email digest",
+            NotifierKind::Webhook => "webhook",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+pub trait Notifier {
+    /// Sends a plain text message, e.g. for logging
+    fn send_message(&self, message: &str) -> Result<()>;
+
+    /// Sends a notification for a game which has started, returning an ID for the notification
+    /// if the backend supports updating it later
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>>;
+
+    /// Updates a notification with the current state of the game
+    fn notify_progress(&self, _notification_id: &str, _content: &NotifyContent) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sends the result of a game, updating the notification for it if we have one
+    fn notify_finished(&self, notification_id: Option<&str>, content: &NotifyContent)
+        -> Result<()>;
+
+    /// Sends anything the backend has batched up and is now due. Called after every poll.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The backends which have been configured, keyed by the kind users select them with
+pub fn get_notifiers(config: &Config) -> Result<HashMap<NotifierKind, Box<dyn Notifier>>> {
+    let mut notifiers: HashMap<NotifierKind, Box<dyn Notifier>> = HashMap::new();
+
+    if let Some(ref hook) = config.notify_webhook {
+        notifiers.insert(
+            NotifierKind::Discord,
+            Box::new(DiscordNotifier::new(hook.clone())),
+        );
+    }
+
+    if let Some(ref hook) = config.slack_webhook {
+        notifiers.insert(
+            NotifierKind::Slack,
+            Box::new(SlackNotifier::new(hook.clone())),
+        );
+    }
+
+    if let Some(ref matrix) = config.matrix {
+        notifiers.insert(
+            NotifierKind::Matrix,
+            Box::new(MatrixNotifier::new(matrix.clone())),
+        );
+    }
+
+    if let Some(ref bot_token) = config.telegram_bot_token {
+        notifiers.insert(
+            NotifierKind::Telegram,
+            Box::new(TelegramNotifier::new(bot_token.clone())),
+        );
+    }
+
+    if let Some(ref email) = config.email {
+        notifiers.insert(NotifierKind::Email, Box::new(EmailNotifier::new(email)?));
+        notifiers.insert(
+            NotifierKind::EmailDigest,
+            Box::new(EmailDigestNotifier::new(email, &config.state_dir)?),
+        );
+    }
+
+    // These are configured by each user, so are always available
+    notifiers.insert(NotifierKind::Ntfy, Box::new(NtfyNotifier));
+    notifiers.insert(NotifierKind::Gotify, Box::new(GotifyNotifier));
+    notifiers.insert(NotifierKind::Webhook, Box::new(WebhookNotifier));
+
+    Ok(notifiers)
+}
+
+/// A backend rejected a request because we've sent too many, and it can be retried after the delay
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limited, retry after {:?}", self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// How long to wait when rate limited without being told how long for
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+fn header_secs(response: &Response, name: &str) -> Option<Duration> {
+    let secs = response
+        .headers()
+        .get(name)?
+        .to_str()
+        .ok()?
+        .parse::<f64>()
+        .ok()?;
+
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+pub trait CheckResponse: Sized {
+    /// Like `error_for_status`, but returns `RateLimited` for a 429 so it can be retried later
+    fn check_status(self) -> Result<Self>;
+}
+
+impl CheckResponse for Response {
+    fn check_status(self) -> Result<Self> {
+        if self.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = header_secs(&self, "retry-after").unwrap_or(DEFAULT_RETRY_AFTER);
+
+            return Err(RateLimited { retry_after }.into());
+        }
+
+        Ok(self.error_for_status()?)
+    }
+}
+
+/// Sends a request to a URL which is a secret, such as a user's ntfy topic or webhook, leaving the
+/// URL out of any error since errors are logged
+pub fn send_to_secret_url(request: RequestBuilder) -> Result<Response> {
+    request
+        .send()
+        .map_err(reqwest::Error::without_url)?
+        .check_status()
+        .map_err(|e| match e.downcast::<reqwest::Error>() {
+            Ok(e) => e.without_url().into(),
+            Err(e) => e,
+        })
+}
+
+/// Follows the `X-RateLimit-*` headers of a backend's responses, so requests are held back once
+/// we've used up the limit rather than being rejected
+#[derive(Default)]
+pub struct RateLimit {
+    reset_at: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    /// Returns `RateLimited` if the limit hasn't reset yet
+    pub fn check(&self) -> Result<()> {
+        let reset_at = *self.reset_at.lock().unwrap();
+
+        match reset_at {
+            Some(reset_at) if reset_at > Instant::now() => Err(RateLimited {
+                retry_after: reset_at - Instant::now(),
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn update(&self, response: &Response) {
+        let remaining = response
+            .headers()
+            .get("x-ratelimit-remaining")
+            .and_then(|r| r.to_str().ok());
+
+        if remaining == Some("0") {
+            if let Some(reset_after) = header_secs(response, "x-ratelimit-reset-after") {
+                *self.reset_at.lock().unwrap() = Some(Instant::now() + reset_after);
+            }
+        }
+    }
+}
+
+/// Who to send a notification to, in whatever form their backend needs
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Recipient {
+    /// A user of a chat or email backend, e.g. a Discord user ID or email address
+    User(String),
+    /// A user's ntfy topic or Gotify server
+    Push(PushTarget),
+    /// A user's webhook, with the request to send to it
+    Webhook(WebhookTarget),
+}
+
+impl Recipient {
+    pub fn user_id(&self) -> Result<&str> {
+        match self {
+            Recipient::User(user_id) => Ok(user_id),
+            _ => bail!("Expected a user, not {:?}", self),
+        }
+    }
+
+    pub fn push_target(&self) -> Result<&PushTarget> {
+        match self {
+            Recipient::Push(target) => Ok(target),
+            _ => bail!("Expected a push target, not {:?}", self),
+        }
+    }
+
+    pub fn webhook_target(&self) -> Result<&WebhookTarget> {
+        match self {
+            Recipient::Webhook(target) => Ok(target),
+            _ => bail!("Expected a webhook, not {:?}", self),
+        }
+    }
+}
+
+/// Sending failed for some recipients but not others, so only the ones which failed should be
+/// retried
+#[derive(Debug)]
+pub struct PartiallySent {
+    pub failed: HashSet<Recipient>,
+    /// The notification for the recipients which were sent to, if the backend returns one
+    pub notification_id: Option<String>,
+    /// The first error
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for PartiallySent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unable to send to {} recipients", self.failed.len())
+    }
+}
+
+impl std::error::Error for PartiallySent {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+impl PartiallySent {
+    /// Adds the notification sent to those who did get it, if `error` is a `PartiallySent`
+    pub fn with_notification_id(
+        error: anyhow::Error,
+        notification_id: Option<String>,
+    ) -> anyhow::Error {
+        match error.downcast::<PartiallySent>() {
+            Ok(partial) => PartiallySent {
+                notification_id,
+                ..partial
+            }
+            .into(),
+            Err(error) => error,
+        }
+    }
+}
+
+/// A recipient the backend can never send to, e.g. a malformed email address
+#[derive(Debug)]
+pub struct InvalidRecipient(pub String);
+
+impl std::fmt::Display for InvalidRecipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid recipient: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRecipient {}
+
+/// Sends to each recipient in turn. Delivery to the others continues if one fails, but the first
+/// error is returned, as `PartiallySent` if anyone was sent to.
+pub fn send_to_each<T>(
+    content: &NotifyContent,
+    mut send: impl FnMut(&Recipient) -> Result<T>,
+) -> Result<Vec<T>> {
+    let mut sent = vec![];
+    let mut failed = HashSet::new();
+    let mut first_error = None;
+
+    for recipient in content.sorted_recipients() {
+        match send(recipient) {
+            Ok(result) => sent.push(result),
+            Err(e) => {
+                failed.insert(recipient.clone());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    match first_error {
+        None => Ok(sent),
+        // Nobody has it yet, so it's no different to failing to send at all
+        Some(error) if sent.is_empty() => Err(error),
+        Some(error) => Err(PartiallySent {
+            failed,
+            notification_id: None,
+            error,
+        }
+        .into()),
+    }
+}
+
+/// Combines the notifications sent to separate recipients into one ID, for backends which send
+/// each recipient their own message
+pub fn join_notification_ids(ids: impl IntoIterator<Item = String>) -> Option<String> {
+    let ids = ids
+        .into_iter()
+        .filter(|id| !id.is_empty())
+        .collect::<Vec<_>>();
+
+    (!ids.is_empty()).then(|| ids.join(";"))
+}
+
+/// The IDs which were combined by `join_notification_ids`
+pub fn split_notification_id(notification_id: &str) -> impl Iterator<Item = &str> {
+    notification_id.split(';').filter(|id| !id.is_empty())
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NotifyContent {
     pub white_player: CcrlLivePlayer,
     pub black_player: CcrlLivePlayer,
     pub room: CcrlLiveRoom,
     pub tournament: String,
-    pub mentions: HashSet<String>,
+    pub opening: String,
+    /// Who to notify through the backend this is sent to
+    pub mentions: HashSet<Recipient>,
+    /// The subscribed engines playing in this game
+    pub engines: BTreeSet<String>,
+
+    pub result: GameResult,
+    pub termination: Option<String>,
+    pub plies: usize,
+    pub score: Option<Score>,
+}
+
+impl NotifyContent {
+    pub fn new(
+        room: &CcrlLiveRoom,
+        game: &Pgn,
+        mentions: HashSet<Recipient>,
+        engines: BTreeSet<String>,
+    ) -> Self {
+        Self {
+            white_player: game.white_player.clone(),
+            black_player: game.black_player.clone(),
+            tournament: game.site.clone(),
+            opening: game.opening_line(),
+            room: room.clone(),
+            mentions,
+            engines,
+            result: game.result,
+            termination: game.termination.clone(),
+            plies: game.moves.len(),
+            score: game.latest_score(),
+        }
+    }
+
+    /// The recipients in a stable order
+    pub fn sorted_recipients(&self) -> Vec<&Recipient> {
+        let mut recipients = self.mentions.iter().collect::<Vec<_>>();
+        recipients.sort();
+        recipients
+    }
+
+    /// The IDs of the users to mention, in a stable order
+    pub fn sorted_mentions(&self) -> Vec<&str> {
+        self.sorted_recipients()
+            .into_iter()
+            .filter_map(|recipient| recipient.user_id().ok())
+            .collect()
+    }
+
+    pub fn move_number(&self) -> usize {
+        self.plies.div_ceil(2)
+    }
+
+    /// The result and how the game ended, if known
+    pub fn result_description(&self) -> String {
+        let result = if self.result.is_finished() {
+            self.result.to_string()
+        } else {
+            // The room moved on before we saw the final result
+            "Unknown".to_string()
+        };
+
+        match &self.termination {
+            Some(termination) => format!("{} ({})", result, termination),
+            None => result,
+        }
+    }
 }
 
-pub fn notify(config: &Config, content: NotifyContent) -> Result<()> {
-    let mentions_str = if !content.mentions.is_empty() {
-        "   cc. ".to_string()
-            + content
-                .mentions
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::ccrl_pgn;
+
+    /// Content for a finished game, shared by the backends' tests
+    pub fn content(mentions: &[&str], engines: &[&str]) -> NotifyContent {
+        let game = ccrl_pgn::get_pgn_info(
+            r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "RookieMonster 1.9.9 64-bit"]
+[Black "Betsabe_II 2023"]
+[Result "1-0"]
+
+1. d4 {(Book)} Nf6 {(Book)} 2. c4 {(Nc3 d5) -0.41/18 1736170414}"#,
+        )
+        .unwrap();
+
+        NotifyContent::new(
+            &CcrlLiveRoom::new("1"),
+            &game,
+            mentions
                 .iter()
-                .map(|m| format!("<@!{}>", m))
-                .collect::<Vec<_>>()
-                .join(" ")
-                .as_str()
-    } else {
-        String::new()
-    };
-
-    discord::send_message(
-        &config.notify_webhook,
-        &format!(
-            "[`{} - {}`]({}) `{}` vs. `{}`{}",
-            content.room.code(),
-            content.tournament,
-            content.room.url(),
-            content.white_player,
-            content.black_player,
-            mentions_str
-        ),
-    )
+                .map(|m| Recipient::User(m.to_string()))
+                .collect(),
+            engines.iter().map(|e| e.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_send_to_each_reports_who_failed() {
+        let content = content(&["1", "2", "3"], &[]);
+
+        let error = send_to_each(&content, |recipient| match recipient.user_id()? {
+            "2" => bail!("blocked"),
+            _ => Ok(()),
+        })
+        .unwrap_err();
+
+        let partial = error.downcast_ref::<PartiallySent>().unwrap();
+        assert_eq!(
+            partial.failed,
+            HashSet::from([Recipient::User("2".to_string())])
+        );
+        assert_eq!(partial.error.to_string(), "blocked");
+
+        let error = send_to_each(&content, |_| -> Result<()> { bail!("down") }).unwrap_err();
+        assert!(!error.is::<PartiallySent>());
+    }
+
+    #[test]
+    fn test_mentions_are_sorted() {
+        assert_eq!(content(&["2", "1"], &[]).sorted_mentions(), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn test_result_description() {
+        let mut content = content(&[], &[]);
+        assert_eq!(content.result_description(), "1-0");
+
+        content.termination = Some("adjudication".to_string());
+        assert_eq!(content.result_description(), "1-0 (adjudication)");
+
+        content.result = GameResult::InProgress;
+        assert_eq!(content.result_description(), "Unknown (adjudication)");
+    }
 }
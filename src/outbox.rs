@@ -0,0 +1,533 @@
+use crate::notify::{
+    join_notification_ids, InvalidRecipient, Notifier, NotifierKind, NotifyContent, PartiallySent,
+    RateLimited,
+};
+use crate::state::write_atomically;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const OUTBOX_FILE: &str = "outbox.json";
+
+pub const MAX_ATTEMPTS: u32 = 10;
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotificationEvent {
+    Started,
+    Finished,
+}
+
+/// A notification waiting to be sent by one backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    /// The game's ID, as stored in the state database
+    pub game_id: u64,
+    pub kind: NotifierKind,
+    pub event: NotificationEvent,
+    pub content: NotifyContent,
+    /// For a result, the notification to update, if the backend returned one for the start of the
+    /// game. For a start which only reached some recipients, the notification sent to them.
+    pub notification_id: Option<String>,
+    /// Failed attempts so far, not counting being rate limited
+    attempts: u32,
+    /// Unix time after which to try again
+    next_attempt: u64,
+}
+
+pub enum Outcome {
+    /// `notification_id` is the ID the backend returned, if any
+    Delivered {
+        delivery: Delivery,
+        notification_id: Option<String>,
+    },
+    Retrying {
+        delivery: Delivery,
+        error: anyhow::Error,
+        retry_in: Duration,
+    },
+    GaveUp {
+        delivery: Delivery,
+        error: anyhow::Error,
+    },
+}
+
+/// Notifications which haven't been delivered yet, kept on disk so they survive restarts. Failed
+/// deliveries are retried with exponential backoff, or after the delay a backend asks for when
+/// rate limiting us.
+pub struct Outbox {
+    path: PathBuf,
+    deliveries: Vec<Delivery>,
+    /// Backends which have rate limited us, and the unix time until which we leave them alone
+    held_until: HashMap<NotifierKind, u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+pub fn backoff(attempts: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// The error from the recipients which failed, if others were sent to
+fn cause(error: &anyhow::Error) -> &anyhow::Error {
+    match error.downcast_ref::<PartiallySent>() {
+        Some(partial) => &partial.error,
+        None => error,
+    }
+}
+
+/// Errors which won't go away by retrying, e.g. a deleted webhook
+fn is_permanent(error: &anyhow::Error) -> bool {
+    error.is::<InvalidRecipient>()
+        || error
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status)
+            .is_some_and(|status| status.is_client_error())
+}
+
+impl Outbox {
+    /// Loads the outbox from the state directory
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        Self::load_from(state_dir.join(OUTBOX_FILE))
+    }
+
+    pub fn load_from(path: PathBuf) -> Result<Self> {
+        let deliveries = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path,
+            deliveries,
+            held_until: HashMap::new(),
+        })
+    }
+
+    /// Writes the outbox to disk
+    pub fn save(&self) -> Result<()> {
+        write_atomically(
+            &self.path,
+            serde_json::to_string(&self.deliveries)?.as_bytes(),
+        )
+    }
+
+    /// Queues a notification, unless the same one is already waiting
+    pub fn push(
+        &mut self,
+        game_id: u64,
+        kind: NotifierKind,
+        event: NotificationEvent,
+        content: NotifyContent,
+        notification_id: Option<String>,
+    ) {
+        let queued = self
+            .deliveries
+            .iter()
+            .any(|d| d.game_id == game_id && d.kind == kind && d.event == event);
+
+        if queued {
+            return;
+        }
+
+        self.deliveries.push(Delivery {
+            game_id,
+            kind,
+            event,
+            content,
+            notification_id,
+            attempts: 0,
+            next_attempt: 0,
+        });
+    }
+
+    /// Whether the start of a game still has notifications waiting
+    pub fn is_pending(&self, game_id: u64) -> bool {
+        self.deliveries
+            .iter()
+            .any(|d| d.game_id == game_id && d.event == NotificationEvent::Started)
+    }
+
+    /// Tries to send every notification which is due
+    pub fn deliver(
+        &mut self,
+        notifiers: &HashMap<NotifierKind, Box<dyn Notifier>>,
+    ) -> Vec<Outcome> {
+        self.deliver_at(notifiers, now())
+    }
+
+    fn deliver_at(
+        &mut self,
+        notifiers: &HashMap<NotifierKind, Box<dyn Notifier>>,
+        now: u64,
+    ) -> Vec<Outcome> {
+        let mut outcomes = vec![];
+        let mut i = 0;
+
+        while i < self.deliveries.len() {
+            let delivery = &self.deliveries[i];
+
+            let held = self
+                .held_until
+                .get(&delivery.kind)
+                .is_some_and(|until| *until > now);
+
+            if delivery.next_attempt > now || held {
+                i += 1;
+                continue;
+            }
+
+            let Some(notifier) = notifiers.get(&delivery.kind) else {
+                // The backend has been removed since this was queued
+                let error = anyhow!("No {} notifier configured", delivery.kind);
+                outcomes.push(Outcome::GaveUp {
+                    delivery: self.deliveries.remove(i),
+                    error,
+                });
+                continue;
+            };
+
+            let result = match delivery.event {
+                NotificationEvent::Started => notifier.notify(&delivery.content),
+                NotificationEvent::Finished => notifier
+                    .notify_finished(delivery.notification_id.as_deref(), &delivery.content)
+                    .map(|_| None),
+            };
+
+            match result {
+                Ok(notification_id) => {
+                    let mut delivery = self.deliveries.remove(i);
+
+                    let notification_id = match delivery.event {
+                        NotificationEvent::Started => join_notification_ids(
+                            delivery
+                                .notification_id
+                                .take()
+                                .into_iter()
+                                .chain(notification_id),
+                        ),
+                        NotificationEvent::Finished => notification_id,
+                    };
+
+                    // A result queued while the start was waiting can now update its notification
+                    if let Some(ref notification_id) = notification_id {
+                        for result in self.deliveries.iter_mut().filter(|d| {
+                            d.game_id == delivery.game_id
+                                && d.kind == delivery.kind
+                                && d.event == NotificationEvent::Finished
+                                && d.notification_id.is_none()
+                        }) {
+                            result.notification_id = Some(notification_id.clone());
+                        }
+                    }
+
+                    outcomes.push(Outcome::Delivered {
+                        delivery,
+                        notification_id,
+                    });
+                }
+                Err(error) => {
+                    let delivery = &mut self.deliveries[i];
+
+                    // Those who were sent to shouldn't get it again when it's retried
+                    if let Some(partial) = error.downcast_ref::<PartiallySent>() {
+                        delivery
+                            .content
+                            .mentions
+                            .retain(|recipient| partial.failed.contains(recipient));
+
+                        if delivery.event == NotificationEvent::Started {
+                            delivery.notification_id = join_notification_ids(
+                                delivery
+                                    .notification_id
+                                    .take()
+                                    .into_iter()
+                                    .chain(partial.notification_id.clone()),
+                            );
+                        }
+                    }
+
+                    let retry_in =
+                        if let Some(limited) = cause(&error).downcast_ref::<RateLimited>() {
+                            limited.retry_after
+                        } else {
+                            delivery.attempts += 1;
+
+                            if delivery.attempts >= MAX_ATTEMPTS || is_permanent(cause(&error)) {
+                                outcomes.push(Outcome::GaveUp {
+                                    delivery: self.deliveries.remove(i),
+                                    error,
+                                });
+                                continue;
+                            }
+
+                            backoff(delivery.attempts)
+                        };
+
+                    delivery.next_attempt = now + retry_in.as_secs_f64().ceil() as u64;
+
+                    if cause(&error).is::<RateLimited>() {
+                        self.held_until.insert(delivery.kind, delivery.next_attempt);
+                    }
+
+                    outcomes.push(Outcome::Retrying {
+                        delivery: delivery.clone(),
+                        error,
+                        retry_in,
+                    });
+
+                    i += 1;
+                }
+            }
+        }
+
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::notify::Recipient;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    type Sent = Vec<(NotificationEvent, Option<String>)>;
+
+    /// Fails with the queued errors before succeeding, recording what it was asked to send
+    #[derive(Clone, Default)]
+    struct FakeNotifier {
+        errors: Arc<Mutex<Vec<anyhow::Error>>>,
+        sent: Arc<Mutex<Sent>>,
+    }
+
+    impl FakeNotifier {
+        fn next_error(&self) -> Result<()> {
+            match self.errors.lock().unwrap().pop() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Notifier for FakeNotifier {
+        fn send_message(&self, _message: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn notify(&self, _content: &NotifyContent) -> Result<Option<String>> {
+            self.next_error()?;
+            self.sent
+                .lock()
+                .unwrap()
+                .push((NotificationEvent::Started, None));
+
+            Ok(Some("message".to_string()))
+        }
+
+        fn notify_finished(
+            &self,
+            notification_id: Option<&str>,
+            _content: &NotifyContent,
+        ) -> Result<()> {
+            self.next_error()?;
+            self.sent.lock().unwrap().push((
+                NotificationEvent::Finished,
+                notification_id.map(str::to_string),
+            ));
+
+            Ok(())
+        }
+    }
+
+    fn setup(
+        name: &str,
+    ) -> (
+        Outbox,
+        FakeNotifier,
+        HashMap<NotifierKind, Box<dyn Notifier>>,
+    ) {
+        let path =
+            std::env::temp_dir().join(format!("ccrl-outbox-{}-{}.json", name, std::process::id()));
+        _ = std::fs::remove_file(&path);
+
+        let notifier = FakeNotifier::default();
+        let mut notifiers: HashMap<NotifierKind, Box<dyn Notifier>> = HashMap::new();
+        notifiers.insert(NotifierKind::Discord, Box::new(notifier.clone()));
+
+        (Outbox::load_from(path).unwrap(), notifier, notifiers)
+    }
+
+    #[test]
+    fn test_failed_delivery_is_retried_with_backoff() {
+        let (mut outbox, notifier, notifiers) = setup("backoff");
+        notifier
+            .errors
+            .lock()
+            .unwrap()
+            .push(anyhow!("connection refused"));
+
+        outbox.push(
+            1,
+            NotifierKind::Discord,
+            NotificationEvent::Started,
+            content(&[], &[]),
+            None,
+        );
+
+        let outcomes = outbox.deliver_at(&notifiers, 1000);
+        assert!(matches!(
+            outcomes[..],
+            [Outcome::Retrying { retry_in, .. }] if retry_in == BASE_BACKOFF
+        ));
+        assert!(outbox.is_pending(1));
+
+        assert!(outbox.deliver_at(&notifiers, 1010).is_empty());
+
+        let outcomes = outbox.deliver_at(&notifiers, 1030);
+        assert!(matches!(outcomes[..], [Outcome::Delivered { .. }]));
+        assert!(!outbox.is_pending(1));
+    }
+
+    #[test]
+    fn test_partial_failure_is_retried_for_those_who_failed() {
+        let (mut outbox, notifier, notifiers) = setup("partial");
+        notifier.errors.lock().unwrap().push(
+            PartiallySent {
+                failed: HashSet::from([Recipient::User("2".to_string())]),
+                notification_id: Some("1:10".to_string()),
+                error: anyhow!("connection refused"),
+            }
+            .into(),
+        );
+
+        outbox.push(
+            1,
+            NotifierKind::Discord,
+            NotificationEvent::Started,
+            content(&["1", "2"], &[]),
+            None,
+        );
+
+        let outcomes = outbox.deliver_at(&notifiers, 1000);
+        let [Outcome::Retrying { delivery, .. }] = &outcomes[..] else {
+            panic!("Expected a retry");
+        };
+        assert_eq!(delivery.content.sorted_mentions(), vec!["2"]);
+        assert_eq!(delivery.notification_id.as_deref(), Some("1:10"));
+        assert_eq!(delivery.attempts, 1);
+
+        let outcomes = outbox.deliver_at(&notifiers, 2000);
+        let [Outcome::Delivered {
+            delivery,
+            notification_id,
+        }] = &outcomes[..]
+        else {
+            panic!("Expected a delivery");
+        };
+        assert_eq!(delivery.content.sorted_mentions(), vec!["2"]);
+        // The messages sent by both attempts are updated with the result
+        assert_eq!(notification_id.as_deref(), Some("1:10;message"));
+    }
+
+    #[test]
+    fn test_rate_limit_delays_backend() {
+        let (mut outbox, notifier, notifiers) = setup("rate-limit");
+        notifier.errors.lock().unwrap().push(
+            RateLimited {
+                retry_after: Duration::from_millis(1500),
+            }
+            .into(),
+        );
+
+        for game_id in [1, 2] {
+            outbox.push(
+                game_id,
+                NotifierKind::Discord,
+                NotificationEvent::Started,
+                content(&[], &[]),
+                None,
+            );
+        }
+
+        // Nothing else is sent to the backend until the limit is over
+        let outcomes = outbox.deliver_at(&notifiers, 1000);
+        assert_eq!(outcomes.len(), 1);
+        assert!(outbox.deliver_at(&notifiers, 1001).is_empty());
+        assert!(notifier.sent.lock().unwrap().is_empty());
+
+        assert_eq!(outbox.deliver_at(&notifiers, 1002).len(), 2);
+        assert_eq!(notifier.sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_result_uses_id_from_delayed_start() {
+        let (mut outbox, notifier, notifiers) = setup("result-id");
+        notifier
+            .errors
+            .lock()
+            .unwrap()
+            .push(anyhow!("connection refused"));
+
+        outbox.push(
+            1,
+            NotifierKind::Discord,
+            NotificationEvent::Started,
+            content(&[], &[]),
+            None,
+        );
+        outbox.deliver_at(&notifiers, 1000);
+
+        outbox.push(
+            1,
+            NotifierKind::Discord,
+            NotificationEvent::Finished,
+            content(&[], &[]),
+            None,
+        );
+        outbox.deliver_at(&notifiers, 2000);
+
+        assert_eq!(
+            notifier.sent.lock().unwrap()[..],
+            [
+                (NotificationEvent::Started, None),
+                (NotificationEvent::Finished, Some("message".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outbox_persists() {
+        let (mut outbox, _, notifiers) = setup("persist");
+
+        outbox.push(
+            1,
+            NotifierKind::Slack,
+            NotificationEvent::Started,
+            content(&["U1"], &[]),
+            None,
+        );
+        outbox.save().unwrap();
+
+        let mut reloaded = Outbox::load_from(outbox.path.clone()).unwrap();
+        assert!(reloaded.is_pending(1));
+
+        // Slack isn't configured, which won't change by retrying
+        let outcomes = reloaded.deliver_at(&notifiers, 1000);
+        assert!(matches!(outcomes[..], [Outcome::GaveUp { .. }]));
+        assert!(!reloaded.is_pending(1));
+
+        _ = std::fs::remove_file(&outbox.path);
+    }
+}
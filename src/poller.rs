@@ -0,0 +1,574 @@
+use crate::ccrl_pgn::Pgn;
+use crate::ccrllive::{CcrlLiveRoom, CurrentGames, GameSource};
+use crate::config::NotifyConfig;
+use crate::log::Logger;
+use crate::notify::{Notifier, NotifierKind, NotifyContent, Recipient};
+use crate::outbox::{Delivery, NotificationEvent, Outbox, Outcome};
+use crate::state::{NotificationStatus, SeenGames};
+use crate::tracker::{GameTracker, TrackedGame, TrackerUpdate};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// Returns the users to mention for this game for each notification backend, and the engines
+/// they're subscribed to
+fn get_mentions(
+    notify_config: &NotifyConfig,
+    room: &CcrlLiveRoom,
+    game: &Pgn,
+) -> (BTreeMap<NotifierKind, HashSet<Recipient>>, BTreeSet<String>) {
+    let mut mentions: BTreeMap<NotifierKind, HashSet<Recipient>> = BTreeMap::new();
+    let mut engines = BTreeSet::new();
+
+    for (engine, user_configs) in &notify_config.engines {
+        if game.has_player(engine) {
+            // Filter users based on their notification rules
+            let matching_users = user_configs
+                .iter()
+                .filter(|user_config| user_config.rules.notify_for_game(engine, room, game))
+                .collect::<Vec<_>>();
+
+            for user_config in &matching_users {
+                for notifier in &user_config.notifiers {
+                    mentions
+                        .entry(notifier.kind())
+                        .or_default()
+                        .insert(notifier.recipient(&user_config.user_id));
+                }
+            }
+
+            if !matching_users.is_empty() {
+                engines.insert(engine.clone());
+            }
+        }
+    }
+
+    (mentions, engines)
+}
+
+/// Everything which happens each time we poll for games: notifying for new games, updating and
+/// finishing notifications for tracked games, and delivering what's in the outbox.
+pub struct Poller<'a> {
+    source: &'a dyn GameSource,
+    notifiers: &'a HashMap<NotifierKind, Box<dyn Notifier>>,
+    log: &'a dyn Logger,
+    game_tracker: GameTracker,
+    seen_games: SeenGames,
+    outbox: Outbox,
+    first_run: bool,
+    /// Backends users have chosen which aren't configured, which have already been warned about
+    missing_notifiers: HashSet<NotifierKind>,
+}
+
+impl<'a> Poller<'a> {
+    pub fn new(
+        source: &'a dyn GameSource,
+        notifiers: &'a HashMap<NotifierKind, Box<dyn Notifier>>,
+        log: &'a dyn Logger,
+        seen_games: SeenGames,
+        outbox: Outbox,
+    ) -> Self {
+        Self {
+            source,
+            notifiers,
+            log,
+            game_tracker: GameTracker::new(),
+            seen_games,
+            outbox,
+            first_run: true,
+            missing_notifiers: HashSet::new(),
+        }
+    }
+
+    pub fn poll(&mut self, notify_config: &NotifyConfig) {
+        match self.source.get_current_games(self.log) {
+            Ok(current_games) => {
+                self.handle_games(notify_config, &current_games);
+
+                if let Err(e) = self.seen_games.touch(&current_games.games) {
+                    self.log
+                        .error(&format!("Unable to update seen games: {:?}", e));
+                }
+            }
+            Err(e) => self
+                .log
+                .warning(&format!("Unable to fetch in-progress games: {:?}", e)),
+        }
+
+        self.deliver_outbox();
+        self.flush_notifiers();
+        self.compact_seen_games();
+    }
+
+    fn handle_games(&mut self, notify_config: &NotifyConfig, current: &CurrentGames) {
+        let log = self.log;
+        let current_games = &current.games;
+
+        if self.first_run {
+            for (room, game) in current_games {
+                log.info(&format!(
+                    "`{}` In progress: `{}` vs `{}` ({} plies)",
+                    room.code(),
+                    game.white_player,
+                    game.black_player,
+                    game.moves.len()
+                ))
+            }
+
+            self.first_run = false;
+        }
+
+        let mut new_games = vec![];
+
+        // Filter out games we've already seen, or are still notifying for.
+        for (room, game) in current_games {
+            if self.outbox.is_pending(game.game_id(room)) {
+                continue;
+            }
+
+            match self.seen_games.contains(room, game) {
+                Ok(true) => {}
+                Ok(false) => new_games.push((room, game)),
+                Err(e) => log.error(&format!(
+                    "`{}` Unable to read seen games: {:?}",
+                    room.code(),
+                    e
+                )),
+            }
+        }
+
+        let TrackerUpdate {
+            progressed,
+            mut finished,
+        } = self.game_tracker.update(current_games);
+
+        finished.extend(self.game_tracker.close_rooms(&current.rooms));
+
+        for (room, game) in new_games {
+            self.notify_started(notify_config, room, game);
+        }
+
+        for tracked in progressed {
+            self.notify_progress(notify_config, &tracked);
+        }
+
+        for tracked in finished {
+            self.notify_finished(notify_config, tracked);
+        }
+    }
+
+    fn notify_started(&mut self, notify_config: &NotifyConfig, room: &CcrlLiveRoom, game: &Pgn) {
+        let log = self.log;
+
+        log.info(&format!(
+            "`{}` - `{}` vs `{}`",
+            room.code(),
+            game.white_player,
+            game.black_player,
+        ));
+
+        let (mentions, engines) = get_mentions(notify_config, room, game);
+
+        if !mentions.is_empty() {
+            log.info(&format!(
+                "`{}` Will notify for {}",
+                room.code(),
+                engines
+                    .iter()
+                    .map(|e| format!("`{}`", e))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ));
+        }
+
+        let game_id = game.game_id(room);
+
+        for (kind, recipients) in mentions {
+            if !self.notifiers.contains_key(&kind) {
+                // Users default to Discord, so this would otherwise be logged for every game
+                if self.missing_notifiers.insert(kind) {
+                    log.warning(&format!(
+                        "No {} notifier configured, so users who chose it won't be notified",
+                        kind
+                    ));
+                }
+                continue;
+            }
+
+            let content = NotifyContent::new(room, game, recipients, engines.clone());
+
+            self.outbox
+                .push(game_id, kind, NotificationEvent::Started, content, None);
+        }
+
+        // Games with notifications are marked seen once they've been delivered
+        let status = if self.outbox.is_pending(game_id) {
+            NotificationStatus::Pending
+        } else {
+            NotificationStatus::Skipped
+        };
+
+        if let Err(e) = self.seen_games.add(room, game, status) {
+            log.error(&format!("Unable to write seen game: {:?}", e));
+        }
+    }
+
+    fn notify_progress(&self, notify_config: &NotifyConfig, tracked: &TrackedGame) {
+        let (mentions, engines) = get_mentions(notify_config, &tracked.room, &tracked.game);
+
+        for (kind, recipients) in mentions {
+            let (Some(notifier), Some(id)) = (
+                self.notifiers.get(&kind),
+                tracked.notification_ids.get(&kind),
+            ) else {
+                continue;
+            };
+
+            let content =
+                NotifyContent::new(&tracked.room, &tracked.game, recipients, engines.clone());
+
+            if let Err(e) = notifier.notify_progress(id, &content) {
+                self.log.warning(&format!(
+                    "`{}` Unable to update {} notify: {:?}",
+                    tracked.room.code(),
+                    kind,
+                    e
+                ));
+            }
+        }
+    }
+
+    fn notify_finished(&mut self, notify_config: &NotifyConfig, tracked: TrackedGame) {
+        let TrackedGame {
+            room,
+            game,
+            notification_ids,
+        } = tracked;
+
+        self.log.info(&format!(
+            "`{}` - `{}` vs `{}` finished `{}` ({} plies)",
+            room.code(),
+            game.white_player,
+            game.black_player,
+            game.result,
+            game.moves.len(),
+        ));
+
+        let (mentions, engines) = get_mentions(notify_config, &room, &game);
+
+        for (kind, recipients) in mentions {
+            if !self.notifiers.contains_key(&kind) {
+                continue;
+            }
+
+            let content = NotifyContent::new(&room, &game, recipients, engines.clone());
+
+            self.outbox.push(
+                game.game_id(&room),
+                kind,
+                NotificationEvent::Finished,
+                content,
+                notification_ids.get(&kind).cloned(),
+            );
+        }
+    }
+
+    /// Records the notification sent for the start of a game, so it's updated as the game goes
+    /// on. Starts which only reached some recipients are recorded too.
+    fn track_notification(&mut self, delivery: &Delivery, notification_id: Option<String>) {
+        if delivery.event != NotificationEvent::Started {
+            return;
+        }
+
+        if let Some(id) = notification_id {
+            self.game_tracker.set_notification_id(
+                &delivery.content.room,
+                delivery.game_id,
+                delivery.kind,
+                id,
+            );
+        }
+    }
+
+    /// Sends what's due from the outbox, and marks games seen once the notifications for their
+    /// start have all been delivered (or given up on)
+    fn deliver_outbox(&mut self) {
+        let log = self.log;
+        let mut started = HashSet::new();
+
+        for outcome in self.outbox.deliver(self.notifiers) {
+            match outcome {
+                Outcome::Delivered {
+                    delivery,
+                    notification_id,
+                } => {
+                    if delivery.event == NotificationEvent::Started {
+                        started.insert(delivery.game_id);
+                    }
+
+                    self.track_notification(&delivery, notification_id);
+                }
+                Outcome::Retrying {
+                    delivery,
+                    error,
+                    retry_in,
+                } => {
+                    self.track_notification(&delivery, delivery.notification_id.clone());
+
+                    log.warning(&format!(
+                        "`{}` Unable to send {} notify, retrying in {:?}: {:?}",
+                        delivery.content.room.code(),
+                        delivery.kind,
+                        retry_in,
+                        error
+                    ));
+                }
+                Outcome::GaveUp { delivery, error } => {
+                    if delivery.event == NotificationEvent::Started {
+                        if let Err(e) = self
+                            .seen_games
+                            .set_status(delivery.game_id, NotificationStatus::Failed)
+                        {
+                            log.error(&format!("Unable to write seen game: {:?}", e));
+                        }
+
+                        started.insert(delivery.game_id);
+                    }
+
+                    self.track_notification(&delivery, delivery.notification_id.clone());
+
+                    log.error(&format!(
+                        "`{}` Unable to send {} notify, giving up: {:?}",
+                        delivery.content.room.code(),
+                        delivery.kind,
+                        error
+                    ));
+                }
+            }
+        }
+
+        if let Err(e) = self.outbox.save() {
+            log.error(&format!("Unable to write outbox to file: {:?}", e));
+        }
+
+        for game_id in started {
+            if self.outbox.is_pending(game_id) {
+                continue;
+            }
+
+            // Leaves games which failed as they are
+            if let Err(e) = self
+                .seen_games
+                .set_status(game_id, NotificationStatus::Notified)
+            {
+                log.error(&format!("Unable to write seen game: {:?}", e));
+            }
+        }
+    }
+
+    fn compact_seen_games(&mut self) {
+        match self.seen_games.compact_if_due() {
+            Ok(Some(removed)) if removed > 0 => self
+                .log
+                .info(&format!("Pruned {} old games from the state", removed)),
+            Ok(_) => {}
+            Err(e) => self
+                .log
+                .error(&format!("Unable to prune old games: {:?}", e)),
+        }
+    }
+
+    fn flush_notifiers(&self) {
+        for (kind, notifier) in self.notifiers {
+            if let Err(e) = notifier.flush() {
+                self.log
+                    .error(&format!("Unable to flush {} notifier: {:?}", kind, e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_notify_config;
+    use crate::fixture::FixtureSource;
+    use crate::log::StdoutLogger;
+    use anyhow::Result;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the warnings it's given
+    #[derive(Default)]
+    struct RecordingLogger {
+        warnings: Mutex<Vec<String>>,
+    }
+
+    impl Logger for RecordingLogger {
+        fn start(&self) {}
+
+        fn info(&self, _msg: &str) {}
+
+        fn warning(&self, msg: &str) {
+            self.warnings.lock().unwrap().push(msg.to_string());
+        }
+
+        fn error(&self, _msg: &str) {}
+
+        fn panic(&self, _info: &std::panic::PanicHookInfo) {}
+    }
+
+    /// Records what it's asked to send
+    #[derive(Clone, Default)]
+    struct RecordingNotifier {
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn send_message(&self, _message: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+            self.sent.lock().unwrap().push(format!(
+                "started {} vs {} for {:?}",
+                content.white_player,
+                content.black_player,
+                content.sorted_mentions()
+            ));
+
+            Ok(Some("n1".to_string()))
+        }
+
+        fn notify_progress(&self, notification_id: &str, content: &NotifyContent) -> Result<()> {
+            self.sent.lock().unwrap().push(format!(
+                "progress {} at ply {}",
+                notification_id, content.plies
+            ));
+
+            Ok(())
+        }
+
+        fn notify_finished(
+            &self,
+            notification_id: Option<&str>,
+            content: &NotifyContent,
+        ) -> Result<()> {
+            self.sent.lock().unwrap().push(format!(
+                "finished {:?} {}",
+                notification_id,
+                content.result_description()
+            ));
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_replayed_game_is_notified_end_to_end() {
+        let dir = std::env::temp_dir().join(format!("ccrl-poller-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state_path = dir.join("state.db");
+        _ = std::fs::remove_file(&state_path);
+        _ = std::fs::remove_file(dir.join("outbox.json"));
+
+        let source =
+            FixtureSource::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/replay"))
+                .unwrap();
+
+        let notifier = RecordingNotifier::default();
+        let mut notifiers: HashMap<NotifierKind, Box<dyn Notifier>> = HashMap::new();
+        notifiers.insert(NotifierKind::Discord, Box::new(notifier.clone()));
+
+        let notify_config = parse_notify_config(
+            r#"{
+  users: {
+    "1": { engines: ["Lunar"] },
+    "2": { engines: ["Rodent"], rules: [{ pattern: "D12", action: "ignore" }] },
+  }
+}"#,
+        )
+        .unwrap();
+
+        let log = StdoutLogger;
+        let mut poller = Poller::new(
+            &source,
+            &notifiers,
+            &log,
+            SeenGames::load_from(&state_path, &log).unwrap(),
+            Outbox::load_from(dir.join("outbox.json")).unwrap(),
+        );
+
+        // The recording has the game starting, progressing, finishing, then staying finished
+        // while the other room leaves book
+        for _ in 0..5 {
+            poller.poll(&notify_config);
+        }
+
+        assert_eq!(
+            notifier.sent.lock().unwrap()[..],
+            [
+                "started Lunar 2.1 64-bit vs Betsabe_II 2023 for [\"1\"]",
+                "progress n1 at ply 5",
+                "finished Some(\"n1\") 1-0 (adjudication)",
+            ]
+        );
+
+        // Both games are recorded as seen, including the ignored one
+        let statuses = source
+            .get_current_games(&log)
+            .unwrap()
+            .games
+            .iter()
+            .map(|(room, game)| poller.seen_games.status(game.game_id(room)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                Some(NotificationStatus::Notified),
+                Some(NotificationStatus::Skipped)
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_notifier_is_warned_about_once() {
+        let dir = std::env::temp_dir().join(format!("ccrl-poller-missing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        _ = std::fs::remove_file(dir.join("state.db"));
+
+        let source =
+            FixtureSource::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/replay"))
+                .unwrap();
+        let notifiers = HashMap::new();
+        let notify_config =
+            parse_notify_config(r#"{ users: { "1": { engines: ["Lunar", "Rodent"] } } }"#).unwrap();
+
+        let log = RecordingLogger::default();
+        let mut poller = Poller::new(
+            &source,
+            &notifiers,
+            &log,
+            SeenGames::load_from(&dir.join("state.db"), &log).unwrap(),
+            Outbox::load_from(dir.join("outbox.json")).unwrap(),
+        );
+
+        // Both rooms are out of book by the end of the recording
+        let games = (0..4)
+            .map(|_| source.get_current_games(&log).unwrap().games)
+            .last()
+            .unwrap();
+        assert_eq!(games.len(), 2);
+
+        for (room, game) in &games {
+            poller.notify_started(&notify_config, room, game);
+        }
+
+        assert_eq!(
+            log.warnings.lock().unwrap()[..],
+            ["No discord notifier configured, so users who chose it won't be notified"]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+use crate::config::{redact_url, REDACTED};
+use crate::notify::{send_to_each, send_to_secret_url, Notifier, NotifyContent};
+use anyhow::Result;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The priority of a game starting, unless the user has chosen their own
+const NTFY_START_PRIORITY: u8 = 4;
+/// Results are less urgent, so are sent at the default priority
+const NTFY_FINISHED_PRIORITY: u8 = 3;
+const GOTIFY_START_PRIORITY: u8 = 8;
+const GOTIFY_FINISHED_PRIORITY: u8 = 5;
+
+/// Where to push to for one user
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PushTarget {
+    /// The ntfy topic's URL, or the Gotify server's message URL
+    pub url: String,
+    /// The Gotify application token
+    pub token: Option<String>,
+    /// The priority of pushes for games starting, if the user has chosen one
+    pub priority: Option<u8>,
+}
+
+// Anyone with the topic or token can push to the user, so neither is logged
+impl std::fmt::Debug for PushTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PushTarget")
+            .field("url", &redact_url(&self.url))
+            .field("token", &self.token.as_ref().map(|_| REDACTED))
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+fn title(content: &NotifyContent) -> String {
+    format!("{} vs. {}", content.white_player, content.black_player)
+}
+
+fn started_message(content: &NotifyContent) -> String {
+    format!(
+        "Game started in {} (room {})",
+        content.tournament,
+        content.room.code()
+    )
+}
+
+fn finished_message(content: &NotifyContent) -> String {
+    let mut message = format!(
+        "Finished {} after {} plies",
+        content.result_description(),
+        content.plies
+    );
+
+    if let Some(score) = content.score {
+        message += &format!(", final eval {}", score);
+    }
+
+    message
+}
+
+/// Sends a push to each user's ntfy topic
+pub struct NtfyNotifier;
+
+impl NtfyNotifier {
+    fn publish(
+        target: &PushTarget,
+        content: &NotifyContent,
+        message: &str,
+        priority: u8,
+    ) -> Result<()> {
+        let mut url = Url::parse(&target.url)?;
+
+        url.query_pairs_mut()
+            .append_pair("title", &title(content))
+            .append_pair("priority", &priority.to_string())
+            .append_pair("click", &content.room.url());
+
+        let client = reqwest::blocking::Client::new();
+
+        send_to_secret_url(client.post(url).body(message.to_string()))?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for NtfyNotifier {
+    fn send_message(&self, _message: &str) -> Result<()> {
+        // There's no shared topic to send plain messages to
+        Ok(())
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        let message = started_message(content);
+
+        send_to_each(content, |recipient| {
+            let target = recipient.push_target()?;
+            let priority = target.priority.unwrap_or(NTFY_START_PRIORITY);
+
+            Self::publish(target, content, &message, priority)
+        })?;
+
+        Ok(None)
+    }
+
+    fn notify_finished(
+        &self,
+        _notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        let message = finished_message(content);
+
+        send_to_each(content, |recipient| {
+            Self::publish(
+                recipient.push_target()?,
+                content,
+                &message,
+                NTFY_FINISHED_PRIORITY,
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Sends a push through each user's Gotify server
+pub struct GotifyNotifier;
+
+impl GotifyNotifier {
+    fn publish(
+        target: &PushTarget,
+        content: &NotifyContent,
+        message: &str,
+        priority: u8,
+    ) -> Result<()> {
+        let client = reqwest::blocking::Client::new();
+
+        // The token is sent as a header rather than in the query, so it stays out of URLs
+        send_to_secret_url(
+            client
+                .post(&target.url)
+                .header("X-Gotify-Key", target.token.as_deref().unwrap_or_default())
+                .json(&json!({
+                    "title": title(content),
+                    "message": message,
+                    "priority": priority,
+                    "extras": {
+                        "client::notification": { "click": { "url": content.room.url() } }
+                    },
+                })),
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for GotifyNotifier {
+    fn send_message(&self, _message: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        let message = started_message(content);
+
+        send_to_each(content, |recipient| {
+            let target = recipient.push_target()?;
+            let priority = target.priority.unwrap_or(GOTIFY_START_PRIORITY);
+
+            Self::publish(target, content, &message, priority)
+        })?;
+
+        Ok(None)
+    }
+
+    fn notify_finished(
+        &self,
+        _notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        let message = finished_message(content);
+
+        send_to_each(content, |recipient| {
+            Self::publish(
+                recipient.push_target()?,
+                content,
+                &message,
+                GOTIFY_FINISHED_PRIORITY,
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::notify::Recipient;
+    use crate::test_server::TestServer;
+
+    fn push_content(targets: &[PushTarget]) -> NotifyContent {
+        let mut content = content(&[], &[]);
+        content.mentions = targets.iter().cloned().map(Recipient::Push).collect();
+        content
+    }
+
+    #[test]
+    fn test_ntfy_pushes_to_each_topic() {
+        let server = TestServer::start(vec![(200, "{}"), (200, "{}")]);
+
+        let first = PushTarget {
+            url: format!("{}/first", server.url),
+            token: None,
+            priority: None,
+        };
+        let second = PushTarget {
+            url: format!("{}/second", server.url),
+            token: None,
+            priority: Some(5),
+        };
+
+        NtfyNotifier
+            .notify(&push_content(&[first, second]))
+            .unwrap();
+
+        let request = server.request();
+        assert_eq!(request.method, "POST");
+        assert_eq!(
+            request.path,
+            "/first?title=RookieMonster+1.9.9+64-bit+vs.+Betsabe_II+2023&priority=4&click=https%3A%2F%2Fccrl.live%2F1"
+        );
+        assert_eq!(request.body, "Game started in 114th Amateur D11 (room 1)");
+
+        assert!(server.request().path.contains("&priority=5&"));
+    }
+
+    #[test]
+    fn test_gotify_result_push() {
+        let server = TestServer::start(vec![(200, "{}")]);
+
+        let target = PushTarget {
+            url: format!("{}/message", server.url),
+            token: Some("abc".to_string()),
+            priority: Some(10),
+        };
+
+        GotifyNotifier
+            .notify_finished(None, &push_content(&[target]))
+            .unwrap();
+
+        let request = server.request();
+        assert_eq!(request.path, "/message");
+        assert_eq!(request.header("x-gotify-key"), Some("abc"));
+
+        let body = request.json();
+        assert_eq!(
+            body["message"],
+            "Finished 1-0 after 3 plies, final eval -0.41"
+        );
+        assert_eq!(body["priority"], GOTIFY_FINISHED_PRIORITY);
+        assert_eq!(
+            body["extras"]["client::notification"]["click"]["url"],
+            "https://ccrl.live/1"
+        );
+    }
+
+    #[test]
+    fn test_debug_output_has_no_secrets() {
+        let target = PushTarget {
+            url: "https://ntfy.sh/secret-topic".to_string(),
+            token: Some("secret-token".to_string()),
+            priority: None,
+        };
+
+        let debug = format!("{:?}", push_content(&[target]));
+        assert!(!debug.contains("secret"), "{}", debug);
+        assert!(debug.contains("https://ntfy.sh/"));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::notify::{send_to_secret_url, Notifier, NotifyContent};
+use anyhow::Result;
+use serde_json::{json, Value};
+
+/// Posts notifications through a Slack incoming webhook. Incoming webhooks can't edit messages,
+/// so results are sent as a new message.
+pub struct SlackNotifier {
+    webhook_url: String,
+}
+
+impl SlackNotifier {
+    pub fn new(webhook_url: String) -> Self {
+        Self { webhook_url }
+    }
+
+    fn call_webhook(&self, body: Value) -> Result<()> {
+        let client = reqwest::blocking::Client::new();
+
+        send_to_secret_url(client.post(&self.webhook_url).json(&body))?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn send_message(&self, message: &str) -> Result<()> {
+        self.call_webhook(json!({ "text": message }))
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        self.call_webhook(started_message(content))?;
+
+        Ok(None)
+    }
+
+    fn notify_finished(
+        &self,
+        _notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        self.call_webhook(finished_message(content))
+    }
+}
+
+fn mentions_str(content: &NotifyContent) -> String {
+    content
+        .sorted_mentions()
+        .iter()
+        .map(|m| format!("<@{}>", m))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn field(name: &str, value: &str) -> Value {
+    json!({ "type": "mrkdwn", "text": format!("*{}*\n{}", name, value) })
+}
+
+fn message(content: &NotifyContent, title: &str, mut fields: Vec<Value>) -> Value {
+    let mut all_fields = vec![
+        field("White", &format!("`{}`", content.white_player)),
+        field("Black", &format!("`{}`", content.black_player)),
+        field("Tournament", &content.tournament),
+        field(
+            "Room",
+            &format!("<{}|{}>", content.room.url(), content.room.code()),
+        ),
+    ];
+    all_fields.append(&mut fields);
+
+    let mut blocks = vec![
+        json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": format!("*<{}|{}>*", content.room.url(), title) }
+        }),
+        json!({ "type": "section", "fields": all_fields }),
+    ];
+
+    if !content.opening.is_empty() {
+        blocks.push(json!({
+            "type": "context",
+            "elements": [{ "type": "mrkdwn", "text": format!("Opening: {}", content.opening) }]
+        }));
+    }
+
+    let mentions = mentions_str(content);
+
+    if !mentions.is_empty() {
+        blocks.push(json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": format!("cc. {}", mentions) }
+        }));
+    }
+
+    json!({
+        // Used for the notification itself, so the mentions need to be here too
+        "text": format!("{} {}", title, mentions).trim_end(),
+        "blocks": blocks,
+    })
+}
+
+fn started_message(content: &NotifyContent) -> Value {
+    let title = format!("{} vs. {}", content.white_player, content.black_player);
+
+    let mut fields = vec![];
+
+    if let Some(score) = content.score {
+        fields.push(field("Eval", &format!("`{}`", score)));
+    }
+
+    message(content, &title, fields)
+}
+
+fn finished_message(content: &NotifyContent) -> Value {
+    let title = format!(
+        "{} vs. {} finished",
+        content.white_player, content.black_player
+    );
+
+    let mut fields = vec![
+        field("Result", &content.result_description()),
+        field("Plies", &content.plies.to_string()),
+    ];
+
+    if let Some(score) = content.score {
+        fields.push(field("Final eval", &format!("`{}`", score)));
+    }
+
+    message(content, &title, fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::test_server::TestServer;
+
+    #[test]
+    fn test_notify_posts_blocks_with_mentions() {
+        let server = TestServer::start(vec![(200, "ok")]);
+        let notifier = SlackNotifier::new(format!("{}/services/hook", server.url));
+
+        let id = notifier
+            .notify(&content(&["U2", "U1"], &["RookieMonster"]))
+            .unwrap();
+        assert_eq!(id, None);
+
+        let request = server.request();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/services/hook");
+        assert_eq!(request.header("content-type"), Some("application/json"));
+
+        let body = request.json();
+        assert_eq!(
+            body["text"],
+            "RookieMonster 1.9.9 64-bit vs. Betsabe_II 2023 <@U1> <@U2>"
+        );
+
+        let blocks = body["blocks"].as_array().unwrap();
+        assert_eq!(blocks.last().unwrap()["text"]["text"], "cc. <@U1> <@U2>");
+        assert_eq!(
+            blocks[1]["fields"][3]["text"],
+            "*Room*\n<https://ccrl.live/1|1>"
+        );
+    }
+
+    #[test]
+    fn test_notify_finished_includes_result() {
+        let server = TestServer::start(vec![(200, "ok")]);
+        let notifier = SlackNotifier::new(server.url.clone());
+
+        notifier
+            .notify_finished(None, &content(&["U1"], &["RookieMonster"]))
+            .unwrap();
+
+        let fields = server.request().json()["blocks"][1]["fields"].clone();
+        assert!(fields
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|f| f["text"] == "*Result*\n1-0"));
+    }
+
+    #[test]
+    fn test_webhook_error_is_returned() {
+        let server = TestServer::start(vec![(404, "no_service")]);
+        let notifier = SlackNotifier::new(server.url.clone());
+
+        assert!(notifier.send_message("hello").is_err());
+        assert_eq!(server.request().json()["text"], "hello");
+    }
+
+    #[test]
+    fn test_errors_do_not_include_url() {
+        let server = TestServer::start(vec![(500, "")]);
+        let notifier = SlackNotifier::new(format!("{}/services/secret", server.url));
+
+        let error = notifier.send_message("hello").unwrap_err();
+        assert!(!format!("{:#}", error).contains("secret"), "{:#}", error);
+    }
+}
@@ -1,44 +1,940 @@
 use crate::ccrl_pgn::Pgn;
-use anyhow::Result;
-use std::collections::HashSet;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use crate::ccrllive::CcrlLiveRoom;
+use crate::log::Logger;
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const STATE_FILE: &str = "state.bin";
+const STATE_DB: &str = "state.db";
+/// Held while we're running so two instances can't share the same state and both notify
+const LOCK_FILE: &str = "ccrl-live-notifier.lock";
 
-pub struct SeenGames {
-    state: HashSet<u64>,
-    file: File,
+/// How long to keep games after they were last seen. Game IDs include the date the game was
+/// played, so old games can never be seen again.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often to prune old games
+const COMPACT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The state file used before the database, which is imported the first time we start
+const LEGACY_STATE_FILE: &str = "state.bin";
+
+/// Prefix for game IDs in the legacy state file. Lines without it are legacy hashes (see
+/// `Pgn::legacy_hash`) written before replays were accounted for.
+const GAME_ID_PREFIX: &str = "v2:";
+
+/// Bumped whenever `SCHEMA` changes, with a step added to `migrate`
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS games (
+    game_id INTEGER PRIMARY KEY,
+    room TEXT,
+    white TEXT,
+    black TEXT,
+    tournament TEXT,
+    date TEXT,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    result TEXT
+);
+
+CREATE TABLE IF NOT EXISTS legacy_hashes (
+    hash INTEGER PRIMARY KEY,
+    imported_at INTEGER NOT NULL
+);
+"#;
+
+/// Whether the notifications for the start of a game have been sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationStatus {
+    /// Waiting in the outbox
+    Pending,
+    Notified,
+    /// Delivery failed too many times
+    Failed,
+    /// Nobody wanted to be notified about it
+    Skipped,
 }
 
-impl SeenGames {
-    pub fn load() -> Result<Self> {
+impl NotificationStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NotificationStatus::Pending => "pending",
+            NotificationStatus::Notified => "notified",
+            NotificationStatus::Failed => "failed",
+            NotificationStatus::Skipped => "skipped",
+        }
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(NotificationStatus::Pending),
+            "notified" => Some(NotificationStatus::Notified),
+            "failed" => Some(NotificationStatus::Failed),
+            "skipped" => Some(NotificationStatus::Skipped),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LegacyImport {
+    pub imported: usize,
+    /// Lines which couldn't be read
+    pub skipped: usize,
+}
+
+/// A game as recorded in the state. Games imported from the legacy state file only have an ID.
+#[derive(Debug, Serialize)]
+pub struct SeenGame {
+    pub game_id: u64,
+    pub room: Option<String>,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub tournament: Option<String>,
+    pub date: Option<String>,
+    /// Unix timestamps
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub status: String,
+    pub result: Option<String>,
+}
+
+/// The result to store for a game, which is only known once it's finished
+fn result(game: &Pgn) -> Option<String> {
+    game.result.is_finished().then(|| game.result.to_string())
+}
+
+fn now() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+/// Opens the database at `path`, creating it if needed. Returns `None` if the file isn't a
+/// database or is damaged, e.g. by a write which was cut short.
+fn open(path: &Path) -> Result<Option<Connection>> {
+    let connection = Connection::open(path)?;
+
+    let check = connection.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0));
+
+    match check {
+        Ok(check) if check == "ok" => {}
+        Ok(_) => return Ok(None),
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if matches!(e.code, ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    // Every transaction is on disk once it's committed, so a crash can only lose the game being
+    // written at the time
+    connection.pragma_update(None, "synchronous", "FULL")?;
+    connection.execute_batch(SCHEMA)?;
+    migrate(&connection)?;
+
+    Ok(Some(connection))
+}
+
+/// Brings a database created by an older version up to date with `SCHEMA`
+fn migrate(connection: &Connection) -> Result<()> {
+    let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    if version < 1 {
+        // Databases from before retention have no import time for legacy hashes. Count them as
+        // imported now, so they're kept for the full retention period.
+        let has_imported_at = connection
+            .prepare("SELECT 1 FROM pragma_table_info('legacy_hashes') WHERE name = 'imported_at'")?
+            .exists([])?;
+
+        if !has_imported_at {
+            connection.execute_batch(
+                "ALTER TABLE legacy_hashes ADD COLUMN imported_at INTEGER NOT NULL DEFAULT 0",
+            )?;
+            connection.execute("UPDATE legacy_hashes SET imported_at = ?1", params![now()?])?;
+        }
+    }
+
+    connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+
+    Ok(())
+}
+
+/// Replaces the file at `path` in one step, so a crash can't leave it half written
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    // Make sure the contents are on disk before the rename is, or a crash could leave an empty
+    // file in place of the old one
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Moves a corrupt database (and its journal) out of the way, returning where it went
+fn quarantine(path: &Path) -> Result<PathBuf> {
+    let mut quarantine_path = path.as_os_str().to_owned();
+    quarantine_path.push(format!(".corrupt-{}", now()?));
+    let quarantine_path = PathBuf::from(quarantine_path);
+
+    std::fs::rename(path, &quarantine_path)?;
+
+    let mut journal_path = path.as_os_str().to_owned();
+    journal_path.push("-journal");
+    let journal_path = PathBuf::from(journal_path);
+
+    if journal_path.exists() {
+        let mut quarantine_journal_path = quarantine_path.as_os_str().to_owned();
+        quarantine_journal_path.push("-journal");
+        std::fs::rename(journal_path, quarantine_journal_path)?;
+    }
+
+    Ok(quarantine_path)
+}
+
+/// An advisory lock on the state directory, released when dropped
+pub struct StateLock {
+    _file: File,
+}
+
+impl StateLock {
+    /// Locks the state directory, creating it if needed. Fails if another instance has it locked.
+    pub fn acquire(state_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(state_dir)?;
+
+        let path = state_dir.join(LOCK_FILE);
         let mut file = OpenOptions::new()
             .create(true)
-            .read(true)
-            .append(true)
-            .open(STATE_FILE)?;
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => bail!(
+                "Another instance is already running with the state in {}",
+                state_dir.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Unable to lock {}", path.display()))
+            }
+        }
+
+        // Note which process has the lock, for anyone wondering
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
 
-        let mut contents = String::new();
-        _ = file.read_to_string(&mut contents);
+        Ok(Self { _file: file })
+    }
+}
+
+/// The games we've seen, kept in a SQLite database
+pub struct SeenGames {
+    connection: Connection,
+    loaded_at: u64,
+    retention: Duration,
+    compacted_at: Option<Instant>,
+}
+
+impl SeenGames {
+    /// Opens the database in the state directory, importing the legacy state file if there is
+    /// one
+    pub fn load(state_dir: &Path, log: &dyn Logger) -> Result<Self> {
+        let mut seen_games = Self::load_from(&state_dir.join(STATE_DB), log)?;
+
+        let legacy_path = state_dir.join(LEGACY_STATE_FILE);
+        if !legacy_path.exists() {
+            return Ok(seen_games);
+        }
+
+        let import = seen_games.import_legacy(&legacy_path)?;
+
+        log.info(&format!(
+            "Imported {} seen games from the legacy state file",
+            import.imported
+        ));
+
+        if import.skipped > 0 {
+            log.warning(&format!(
+                "Skipped {} unreadable lines in the legacy state file",
+                import.skipped
+            ));
+        }
+
+        // Keep the old file around, but don't import it again
+        std::fs::rename(&legacy_path, legacy_path.with_extension("bin.imported"))?;
+
+        Ok(seen_games)
+    }
+
+    /// Opens the existing database in the state directory without writing to it, e.g. to inspect
+    /// it while the notifier is running
+    pub fn open_read_only(state_dir: &Path) -> Result<Self> {
+        Self::open_existing(state_dir, OpenFlags::SQLITE_OPEN_READ_ONLY)
+    }
+
+    /// Opens the existing database in the state directory to change it. Unlike `load`, this
+    /// doesn't import, move or set up anything, so it's safe while the notifier is running.
+    pub fn open_read_write(state_dir: &Path) -> Result<Self> {
+        Self::open_existing(state_dir, OpenFlags::SQLITE_OPEN_READ_WRITE)
+    }
+
+    fn open_existing(state_dir: &Path, flags: OpenFlags) -> Result<Self> {
+        let path = state_dir.join(STATE_DB);
+        if !path.exists() {
+            bail!("No state in {}", state_dir.display());
+        }
+
+        let connection = Connection::open_with_flags(&path, flags)?;
+        let loaded_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        Ok(Self {
+            connection,
+            loaded_at,
+            retention: DEFAULT_RETENTION,
+            compacted_at: None,
+        })
+    }
+
+    /// Opens the database at `path`. A corrupt database is moved aside and replaced with an empty
+    /// one, so we may notify for some games again rather than not starting at all.
+    pub fn load_from(path: &Path, log: &dyn Logger) -> Result<Self> {
+        let connection = match open(path)? {
+            Some(connection) => connection,
+            None => {
+                let quarantine_path = quarantine(path)?;
+
+                log.warning(&format!(
+                    "State database {} is corrupt, moved it to {} and starting afresh",
+                    path.display(),
+                    quarantine_path.display()
+                ));
+
+                open(path)?.context("New state database is corrupt")?
+            }
+        };
+
+        let loaded_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
-        let state = contents
-            .lines()
-            .map(|l| l.parse::<u64>().expect("Bad state file"))
-            .collect();
+        Ok(Self {
+            connection,
+            loaded_at,
+            retention: DEFAULT_RETENTION,
+            compacted_at: None,
+        })
+    }
+
+    /// Sets how long to keep games after they were last seen
+    pub fn with_retention(self, retention: Duration) -> Self {
+        Self { retention, ..self }
+    }
+
+    /// Imports the IDs from a legacy state file, skipping lines which can't be read
+    pub fn import_legacy(&mut self, path: &Path) -> Result<LegacyImport> {
+        // A crash while appending could have left a partial line, possibly cut off in the middle
+        // of a character
+        let contents = String::from_utf8_lossy(&std::fs::read(path)?).into_owned();
+        let now = now()?;
+
+        let mut import = LegacyImport::default();
+
+        let transaction = self.connection.transaction()?;
+
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            let inserted = match line.strip_prefix(GAME_ID_PREFIX) {
+                Some(id) => id.parse::<u64>().ok().map(|id| {
+                    transaction.execute(
+                        "INSERT OR IGNORE INTO games (game_id, first_seen, last_seen, status)
+                         VALUES (?1, ?2, ?2, ?3)",
+                        params![id as i64, now, NotificationStatus::Notified.as_str()],
+                    )
+                }),
+                None => line.parse::<u64>().ok().map(|hash| {
+                    transaction.execute(
+                        "INSERT OR IGNORE INTO legacy_hashes (hash, imported_at) VALUES (?1, ?2)",
+                        params![hash as i64, now],
+                    )
+                }),
+            };
+
+            match inserted {
+                Some(result) => {
+                    result?;
+                    import.imported += 1;
+                }
+                None => import.skipped += 1,
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(import)
+    }
+
+    /// Whether we've dealt with a game, so shouldn't notify for it again. Games which are still
+    /// waiting to be notified don't count.
+    pub fn contains(&self, room: &CcrlLiveRoom, game: &Pgn) -> Result<bool> {
+        if let Some(status) = self.status(game.game_id(room))? {
+            return Ok(status != NotificationStatus::Pending);
+        }
+
+        // Legacy hashes can't tell a game apart from its replays, so they only count for games
+        // which started before we loaded them. Replays started since will be notified.
+        let started_before_load = game
+            .start_timestamp()
+            .is_none_or(|started| started < self.loaded_at);
 
-        Ok(Self { state, file })
+        if !started_before_load {
+            return Ok(false);
+        }
+
+        let legacy = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM legacy_hashes WHERE hash = ?1",
+                params![game.legacy_hash() as i64],
+                |_| Ok(()),
+            )
+            .optional()?;
+
+        Ok(legacy.is_some())
     }
 
-    pub fn contains(&self, game: &Pgn) -> bool {
-        self.state.contains(&game.as_hash())
+    /// The notification status of a game, if we've seen it
+    pub fn status(&self, game_id: u64) -> Result<Option<NotificationStatus>> {
+        let status = self
+            .connection
+            .query_row(
+                "SELECT status FROM games WHERE game_id = ?1",
+                params![game_id as i64],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        match status {
+            Some(status) => match NotificationStatus::parse(&status) {
+                Some(status) => Ok(Some(status)),
+                None => bail!("Unknown status `{}` for game {}", status, game_id),
+            },
+            None => Ok(None),
+        }
     }
 
-    pub fn add(&mut self, game: &Pgn) -> Result<()> {
-        self.state.insert(game.as_hash());
+    /// Records a game we've just seen for the first time
+    pub fn add(
+        &mut self,
+        room: &CcrlLiveRoom,
+        game: &Pgn,
+        status: NotificationStatus,
+    ) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO games
+                 (game_id, room, white, black, tournament, date, first_seen, last_seen, status, result)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8, ?9)
+             ON CONFLICT (game_id) DO UPDATE SET
+                 room = excluded.room,
+                 white = excluded.white,
+                 black = excluded.black,
+                 tournament = excluded.tournament,
+                 date = excluded.date,
+                 last_seen = excluded.last_seen,
+                 status = excluded.status,
+                 result = excluded.result",
+            params![
+                game.game_id(room) as i64,
+                room.code(),
+                game.white_player.to_string(),
+                game.black_player.to_string(),
+                game.site,
+                game.date,
+                now()?,
+                status.as_str(),
+                result(game),
+            ],
+        )?;
 
-        writeln!(&mut self.file, "{}", game.as_hash())?;
+        Ok(())
+    }
+
+    /// Records whether the notifications for a game were delivered. Once any of them has failed,
+    /// the game stays failed.
+    pub fn set_status(&mut self, game_id: u64, status: NotificationStatus) -> Result<()> {
+        self.connection.execute(
+            "UPDATE games SET status = ?2 WHERE game_id = ?1 AND status != ?3",
+            params![
+                game_id as i64,
+                status.as_str(),
+                NotificationStatus::Failed.as_str()
+            ],
+        )?;
 
         Ok(())
     }
+
+    /// Every game in the state, oldest first
+    pub fn games(&self) -> Result<Vec<SeenGame>> {
+        let mut statement = self.connection.prepare(
+            "SELECT game_id, room, white, black, tournament, date, first_seen, last_seen, status, result
+             FROM games ORDER BY first_seen, game_id",
+        )?;
+
+        let games = statement
+            .query_map([], |row| {
+                Ok(SeenGame {
+                    game_id: row.get::<_, i64>(0)? as u64,
+                    room: row.get(1)?,
+                    white: row.get(2)?,
+                    black: row.get(3)?,
+                    tournament: row.get(4)?,
+                    date: row.get(5)?,
+                    first_seen: row.get::<_, i64>(6)? as u64,
+                    last_seen: row.get::<_, i64>(7)? as u64,
+                    status: row.get(8)?,
+                    result: row.get(9)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(games)
+    }
+
+    /// Forgets a game, by its ID or legacy hash, so it will be notified again if it's seen.
+    /// Returns whether there was anything to forget.
+    pub fn forget(&mut self, id: u64) -> Result<bool> {
+        let transaction = self.connection.transaction()?;
+
+        let removed = transaction
+            .execute("DELETE FROM games WHERE game_id = ?1", params![id as i64])?
+            + transaction.execute(
+                "DELETE FROM legacy_hashes WHERE hash = ?1",
+                params![id as i64],
+            )?;
+
+        transaction.commit()?;
+
+        Ok(removed > 0)
+    }
+
+    /// Removes games last seen before `cutoff` (a Unix timestamp), apart from those still being
+    /// notified. Returns how many were removed.
+    pub fn prune(&mut self, cutoff: u64) -> Result<usize> {
+        let transaction = self.connection.transaction()?;
+
+        let removed = transaction.execute(
+            "DELETE FROM games WHERE last_seen < ?1 AND status != ?2",
+            params![cutoff as i64, NotificationStatus::Pending.as_str()],
+        )?;
+        // Legacy hashes can only match games which started before they were imported
+        transaction.execute(
+            "DELETE FROM legacy_hashes WHERE imported_at < ?1",
+            params![cutoff as i64],
+        )?;
+
+        transaction.commit()?;
+
+        Ok(removed)
+    }
+
+    /// Prunes games older than the retention period and shrinks the database, if it hasn't been
+    /// done recently. Returns how many games were removed, if it was done.
+    pub fn compact_if_due(&mut self) -> Result<Option<usize>> {
+        if self
+            .compacted_at
+            .is_some_and(|compacted_at| compacted_at.elapsed() < COMPACT_INTERVAL)
+        {
+            return Ok(None);
+        }
+
+        self.compacted_at = Some(Instant::now());
+
+        let cutoff = (now()? as u64).saturating_sub(self.retention.as_secs());
+        let removed = self.prune(cutoff)?;
+
+        if removed > 0 {
+            self.connection.execute_batch("VACUUM")?;
+        }
+
+        Ok(Some(removed))
+    }
+
+    /// Updates when the games currently being played were last seen, and their results
+    pub fn touch(&mut self, current_games: &[(CcrlLiveRoom, Pgn)]) -> Result<()> {
+        let now = now()?;
+        let transaction = self.connection.transaction()?;
+
+        for (room, game) in current_games {
+            transaction.execute(
+                "UPDATE games SET last_seen = ?2, result = ?3 WHERE game_id = ?1",
+                params![game.game_id(room) as i64, now, result(game)],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccrl_pgn;
+    use crate::log::StdoutLogger;
+
+    fn game(result: &str) -> Pgn {
+        ccrl_pgn::get_pgn_info(&format!(
+            r#"[Site "114th Amateur D11"]
+[Date "2025.01.06"]
+[White "Lunar"]
+[Black "Betsabe_II 2023"]
+[Result "{result}"]
+
+1. d4 {{(Book)}} Nf6 {{(Nc3 d5) -0.41/18 1736170414}}"#
+        ))
+        .unwrap()
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ccrl-state-{}-{}", name, std::process::id()));
+        _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_pending_games_are_not_seen_until_notified() {
+        let path = temp_path("pending.db");
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        let room = CcrlLiveRoom::new("1");
+        let game = game("*");
+
+        assert!(!seen_games.contains(&room, &game).unwrap());
+
+        seen_games
+            .add(&room, &game, NotificationStatus::Pending)
+            .unwrap();
+        assert!(!seen_games.contains(&room, &game).unwrap());
+
+        seen_games
+            .set_status(game.game_id(&room), NotificationStatus::Notified)
+            .unwrap();
+        assert!(seen_games.contains(&room, &game).unwrap());
+
+        // The same game replayed in another room is a different game
+        assert!(!seen_games.contains(&CcrlLiveRoom::new("2"), &game).unwrap());
+
+        // A failure isn't overwritten by a later delivery
+        seen_games
+            .set_status(game.game_id(&room), NotificationStatus::Failed)
+            .unwrap();
+        seen_games
+            .set_status(game.game_id(&room), NotificationStatus::Notified)
+            .unwrap();
+        assert_eq!(
+            seen_games.status(game.game_id(&room)).unwrap(),
+            Some(NotificationStatus::Failed)
+        );
+
+        // It's still seen after a restart
+        drop(seen_games);
+        let seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        assert!(seen_games.contains(&room, &game).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_touch_records_result() {
+        let path = temp_path("touch.db");
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        let room = CcrlLiveRoom::new("1");
+
+        seen_games
+            .add(&room, &game("*"), NotificationStatus::Skipped)
+            .unwrap();
+        seen_games.touch(&[(room.clone(), game("1-0"))]).unwrap();
+
+        let (white, result) = seen_games
+            .connection
+            .query_row("SELECT white, result FROM games", [], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .unwrap();
+        assert_eq!(white, "Lunar");
+        assert_eq!(result, "1-0");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_state_is_imported() {
+        let room = CcrlLiveRoom::new("1");
+        let game = game("*");
+
+        let legacy_path = temp_path("state.bin");
+        std::fs::write(
+            &legacy_path,
+            format!(
+                "{}\nnot a hash\n{}{}\n",
+                game.legacy_hash(),
+                GAME_ID_PREFIX,
+                game.game_id(&CcrlLiveRoom::new("2"))
+            ),
+        )
+        .unwrap();
+
+        let path = temp_path("import.db");
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+
+        assert_eq!(
+            seen_games.import_legacy(&legacy_path).unwrap(),
+            LegacyImport {
+                imported: 2,
+                skipped: 1,
+            }
+        );
+
+        // The game started before we loaded the legacy hash, so it's covered by it
+        assert!(seen_games.contains(&room, &game).unwrap());
+        assert!(seen_games.contains(&CcrlLiveRoom::new("2"), &game).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&legacy_path).unwrap();
+    }
+
+    /// Files in the same directory as `path` whose names start with its name
+    fn files_like(path: &Path) -> Vec<String> {
+        let name = path.file_name().unwrap().to_str().unwrap();
+
+        std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|file| file.starts_with(name))
+            .collect()
+    }
+
+    fn remove_files_like(path: &Path) {
+        for file in files_like(path) {
+            std::fs::remove_file(path.with_file_name(file)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_partially_written_database_is_quarantined() {
+        let path = temp_path("truncated.db");
+        let room = CcrlLiveRoom::new("1");
+        let game = game("*");
+
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        seen_games
+            .add(&room, &game, NotificationStatus::Notified)
+            .unwrap();
+        drop(seen_games);
+
+        // Simulate a write which only got part way through
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &contents[..contents.len() / 2]).unwrap();
+
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        assert!(!seen_games.contains(&room, &game).unwrap());
+
+        // The new database works, and the old one is kept for inspection
+        seen_games
+            .add(&room, &game, NotificationStatus::Notified)
+            .unwrap();
+        assert!(seen_games.contains(&room, &game).unwrap());
+        assert!(files_like(&path).iter().any(|f| f.contains(".corrupt-")));
+
+        remove_files_like(&path);
+    }
+
+    #[test]
+    fn test_garbage_database_is_quarantined() {
+        let path = temp_path("garbage.db");
+        std::fs::write(&path, "v2:1\nv2:2\n".repeat(100)).unwrap();
+
+        let seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        assert_eq!(seen_games.status(1).unwrap(), None);
+        assert!(files_like(&path).iter().any(|f| f.contains(".corrupt-")));
+
+        remove_files_like(&path);
+    }
+
+    #[test]
+    fn test_legacy_state_with_partial_last_line_is_imported() {
+        let legacy_path = temp_path("partial.bin");
+        let mut contents = b"v2:123\nv2:456\n".to_vec();
+        // A line cut off part way through being written
+        contents.extend_from_slice(b"v2:78\xff");
+        std::fs::write(&legacy_path, contents).unwrap();
+
+        let path = temp_path("partial.db");
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+
+        assert_eq!(
+            seen_games.import_legacy(&legacy_path).unwrap(),
+            LegacyImport {
+                imported: 2,
+                skipped: 1,
+            }
+        );
+        assert_eq!(
+            seen_games.status(456).unwrap(),
+            Some(NotificationStatus::Notified)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&legacy_path).unwrap();
+    }
+
+    #[test]
+    fn test_prune_keeps_recent_and_pending_games() {
+        let path = temp_path("prune.db");
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        let game = game("*");
+
+        for (code, status) in [
+            ("1", NotificationStatus::Notified),
+            ("2", NotificationStatus::Pending),
+            ("3", NotificationStatus::Skipped),
+        ] {
+            seen_games
+                .add(&CcrlLiveRoom::new(code), &game, status)
+                .unwrap();
+        }
+
+        let now = now().unwrap() as u64;
+
+        assert_eq!(seen_games.prune(now - 60).unwrap(), 0);
+        assert_eq!(seen_games.prune(now + 60).unwrap(), 2);
+
+        assert_eq!(
+            seen_games
+                .status(game.game_id(&CcrlLiveRoom::new("2")))
+                .unwrap(),
+            Some(NotificationStatus::Pending)
+        );
+        assert_eq!(
+            seen_games
+                .status(game.game_id(&CcrlLiveRoom::new("1")))
+                .unwrap(),
+            None
+        );
+
+        // Compaction happens straight away, then waits for the interval
+        assert_eq!(seen_games.compact_if_due().unwrap(), Some(0));
+        assert_eq!(seen_games.compact_if_due().unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_state_lock_is_exclusive() {
+        let dir = std::env::temp_dir().join(format!("ccrl-state-lock-{}", std::process::id()));
+
+        let lock = StateLock::acquire(&dir).unwrap();
+        assert!(StateLock::acquire(&dir).is_err());
+
+        drop(lock);
+        StateLock::acquire(&dir).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_forgotten_games_are_notified_again() {
+        let path = temp_path("forget.db");
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        let room = CcrlLiveRoom::new("1");
+        let game = game("*");
+        let game_id = game.game_id(&room);
+
+        seen_games
+            .add(&room, &game, NotificationStatus::Notified)
+            .unwrap();
+
+        let games = seen_games.games().unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].game_id, game_id);
+        assert_eq!(games[0].room.as_deref(), Some("1"));
+        assert_eq!(games[0].tournament.as_deref(), Some("114th Amateur D11"));
+
+        assert!(seen_games.forget(game_id).unwrap());
+        assert!(!seen_games.forget(game_id).unwrap());
+        assert!(!seen_games.contains(&room, &game).unwrap());
+        assert!(seen_games.games().unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_database_without_import_times_is_migrated() {
+        let path = temp_path("migrate.db");
+
+        // The schema before retention was added
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE legacy_hashes (hash INTEGER PRIMARY KEY);
+                 INSERT INTO legacy_hashes (hash) VALUES (42);",
+            )
+            .unwrap();
+        drop(connection);
+
+        let mut seen_games = SeenGames::load_from(&path, &StdoutLogger).unwrap();
+        let now = now().unwrap() as u64;
+
+        // The hash is kept until the retention period after the migration has passed
+        seen_games.prune(now - 60).unwrap();
+        assert!(seen_games.forget(42).unwrap());
+
+        // Opening it again doesn't try to migrate it again
+        drop(seen_games);
+        SeenGames::load_from(&path, &StdoutLogger).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_opening_existing_state_changes_nothing_else() {
+        let dir = std::env::temp_dir().join(format!("ccrl-state-existing-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert!(SeenGames::open_read_only(&dir).is_err());
+
+        let mut seen_games = SeenGames::load_from(&dir.join(STATE_DB), &StdoutLogger).unwrap();
+        seen_games
+            .add(
+                &CcrlLiveRoom::new("1"),
+                &game("*"),
+                NotificationStatus::Notified,
+            )
+            .unwrap();
+        drop(seen_games);
+
+        std::fs::write(dir.join(LEGACY_STATE_FILE), "v2:1\n").unwrap();
+
+        let mut seen_games = SeenGames::open_read_only(&dir).unwrap();
+        assert_eq!(seen_games.games().unwrap().len(), 1);
+        assert!(seen_games.forget(1).is_err());
+
+        let mut seen_games = SeenGames::open_read_write(&dir).unwrap();
+        assert!(!seen_games.forget(1).unwrap());
+
+        // The legacy state is left for the notifier to import
+        assert!(dir.join(LEGACY_STATE_FILE).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,313 @@
+use crate::notify::{
+    html_escape, join_notification_ids, send_to_each, send_to_secret_url, split_notification_id,
+    Notifier, NotifyContent, PartiallySent,
+};
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+
+/// Sends notifications to each user's chat with a Telegram bot
+pub struct TelegramNotifier {
+    api_url: String,
+    bot_token: String,
+}
+
+impl TelegramNotifier {
+    pub fn new(bot_token: String) -> Self {
+        Self {
+            api_url: TELEGRAM_API_URL.to_string(),
+            bot_token,
+        }
+    }
+
+    fn call_method(&self, method: &str, body: Value) -> Result<Value> {
+        let client = reqwest::blocking::Client::new();
+
+        // The URL has the bot token in it
+        let response = send_to_secret_url(
+            client
+                .post(format!("{}/bot{}/{}", self.api_url, self.bot_token, method))
+                .json(&body),
+        )?
+        .json::<Value>()
+        .map_err(reqwest::Error::without_url)?;
+
+        if response["ok"] != json!(true) {
+            bail!("Telegram {} failed: {}", method, response);
+        }
+
+        Ok(response["result"].clone())
+    }
+
+    /// Sends a message to `chat_id`, returning the ID of the message
+    fn send_message_to(&self, chat_id: &str, text: &str) -> Result<i64> {
+        let result = self.call_method(
+            "sendMessage",
+            json!({
+                "chat_id": chat_id,
+                "text": text,
+                "parse_mode": "HTML",
+                "link_preview_options": { "is_disabled": true },
+            }),
+        )?;
+
+        result["message_id"]
+            .as_i64()
+            .ok_or_else(|| anyhow!("No message ID in Telegram response: {}", result))
+    }
+
+    fn edit_message(&self, chat_id: &str, message_id: &str, text: &str) -> Result<()> {
+        self.call_method(
+            "editMessageText",
+            json!({
+                "chat_id": chat_id,
+                "message_id": message_id.parse::<i64>()?,
+                "text": text,
+                "parse_mode": "HTML",
+                "link_preview_options": { "is_disabled": true },
+            }),
+        )?;
+
+        Ok(())
+    }
+
+    /// Sends `text` to every chat, returning the messages which were sent as a notification ID. If
+    /// some chats fail, the messages sent to the others are returned with the error.
+    fn send_to_chats(&self, content: &NotifyContent, text: &str) -> Result<Option<String>> {
+        let mut sent = vec![];
+
+        let result = send_to_each(content, |recipient| {
+            let chat_id = recipient.user_id()?;
+            let message_id = self.send_message_to(chat_id, text)?;
+
+            sent.push(format!("{}:{}", chat_id, message_id));
+
+            Ok(())
+        });
+
+        let notification_id = join_notification_ids(sent);
+
+        match result {
+            Ok(_) => Ok(notification_id),
+            Err(error) => Err(PartiallySent::with_notification_id(error, notification_id)),
+        }
+    }
+
+    /// Edits the message sent to each chat. Chats without one, because sending to them failed, are
+    /// sent a new message if `send_missing` is set.
+    fn edit_messages(
+        &self,
+        notification_id: &str,
+        content: &NotifyContent,
+        text: &str,
+        send_missing: bool,
+    ) -> Result<()> {
+        let mut messages = HashMap::new();
+
+        for message in split_notification_id(notification_id) {
+            // Chat IDs may contain ':' themselves, so split on the last one
+            let Some((chat_id, message_id)) = message.rsplit_once(':') else {
+                bail!("Invalid Telegram notification ID: {}", notification_id);
+            };
+
+            messages.insert(chat_id, message_id);
+        }
+
+        send_to_each(content, |recipient| {
+            let chat_id = recipient.user_id()?;
+
+            match messages.get(chat_id) {
+                Some(message_id) => self.edit_message(chat_id, message_id, text),
+                None if send_missing => self.send_message_to(chat_id, text).map(|_| ()),
+                None => Ok(()),
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for TelegramNotifier {
+    fn send_message(&self, _message: &str) -> Result<()> {
+        // There's no shared chat to send plain messages to
+        Ok(())
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        self.send_to_chats(content, &progress_message(content))
+    }
+
+    fn notify_progress(&self, notification_id: &str, content: &NotifyContent) -> Result<()> {
+        self.edit_messages(notification_id, content, &progress_message(content), false)
+    }
+
+    fn notify_finished(
+        &self,
+        notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        match notification_id {
+            Some(notification_id) => {
+                self.edit_messages(notification_id, content, &finished_message(content), true)
+            }
+            None => self
+                .send_to_chats(content, &finished_message(content))
+                .map(|_| ()),
+        }
+    }
+}
+
+fn message(content: &NotifyContent, details: &str) -> String {
+    format!(
+        "<a href=\"{}\">{} - {}</a>\n<b>{}</b> vs. <b>{}</b>\n{}",
+        html_escape(&content.room.url()),
+        html_escape(&content.room.code()),
+        html_escape(&content.tournament),
+        html_escape(&content.white_player.to_string()),
+        html_escape(&content.black_player.to_string()),
+        details,
+    )
+}
+
+fn progress_message(content: &NotifyContent) -> String {
+    let mut details = format!("Move {}", content.move_number());
+
+    if let Some(score) = content.score {
+        details += &format!(", eval <code>{}</code>", score);
+    }
+
+    message(content, &details)
+}
+
+fn finished_message(content: &NotifyContent) -> String {
+    let mut details = format!(
+        "Finished <b>{}</b> after {} plies",
+        html_escape(&content.result_description()),
+        content.plies
+    );
+
+    if let Some(score) = content.score {
+        details += &format!(", final eval <code>{}</code>", score);
+    }
+
+    message(content, &details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::test_server::TestServer;
+
+    fn notifier(server: &TestServer) -> TelegramNotifier {
+        TelegramNotifier {
+            api_url: server.url.clone(),
+            bot_token: "token".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_notify_sends_to_each_chat() {
+        let server = TestServer::start(vec![
+            (200, r#"{ "ok": true, "result": { "message_id": 10 } }"#),
+            (200, r#"{ "ok": true, "result": { "message_id": 20 } }"#),
+        ]);
+
+        let notification_id = notifier(&server)
+            .notify(&content(&["-100", "42"], &[]))
+            .unwrap();
+        assert_eq!(notification_id.as_deref(), Some("-100:10;42:20"));
+
+        let request = server.request();
+        assert_eq!(request.path, "/bottoken/sendMessage");
+
+        let body = request.json();
+        assert_eq!(body["chat_id"], "-100");
+        assert_eq!(body["parse_mode"], "HTML");
+        assert!(body["text"].as_str().unwrap().starts_with(
+            "<a href=\"https://ccrl.live/1\">1 - 114th Amateur D11</a>\n<b>RookieMonster 1.9.9 64-bit</b> vs. <b>Betsabe_II 2023</b>"
+        ));
+
+        assert_eq!(server.request().json()["chat_id"], "42");
+    }
+
+    #[test]
+    fn test_notify_finished_edits_each_message() {
+        let server = TestServer::start(vec![
+            (200, r#"{ "ok": true, "result": {} }"#),
+            (200, r#"{ "ok": true, "result": {} }"#),
+        ]);
+
+        notifier(&server)
+            .notify_finished(Some("-100:10;42:20"), &content(&["-100", "42"], &[]))
+            .unwrap();
+
+        let request = server.request();
+        assert_eq!(request.path, "/bottoken/editMessageText");
+
+        let body = request.json();
+        assert_eq!(body["chat_id"], "-100");
+        assert_eq!(body["message_id"], 10);
+        assert!(body["text"]
+            .as_str()
+            .unwrap()
+            .contains("Finished <b>1-0</b> after 3 plies"));
+
+        assert_eq!(server.request().json()["message_id"], 20);
+    }
+
+    #[test]
+    fn test_failed_chat_does_not_stop_others() {
+        let server = TestServer::start(vec![
+            (403, r#"{ "ok": false, "description": "Forbidden" }"#),
+            (200, r#"{ "ok": true, "result": { "message_id": 20 } }"#),
+        ]);
+
+        let error = notifier(&server)
+            .notify(&content(&["-100", "42"], &[]))
+            .unwrap_err();
+
+        server.request();
+        assert_eq!(server.request().json()["chat_id"], "42");
+
+        // The message which was sent is still tracked
+        let partial = error.downcast_ref::<PartiallySent>().unwrap();
+        assert_eq!(partial.notification_id.as_deref(), Some("42:20"));
+    }
+
+    #[test]
+    fn test_result_is_sent_to_chats_the_start_failed_for() {
+        let server = TestServer::start(vec![
+            (200, r#"{ "ok": true, "result": { "message_id": 11 } }"#),
+            (200, r#"{ "ok": true, "result": {} }"#),
+        ]);
+
+        notifier(&server)
+            .notify_finished(Some("42:20"), &content(&["-100", "42"], &[]))
+            .unwrap();
+
+        let request = server.request();
+        assert_eq!(request.path, "/bottoken/sendMessage");
+        assert_eq!(request.json()["chat_id"], "-100");
+
+        let request = server.request();
+        assert_eq!(request.path, "/bottoken/editMessageText");
+        assert_eq!(request.json()["message_id"], 20);
+    }
+
+    #[test]
+    fn test_errors_do_not_include_bot_token() {
+        let server = TestServer::start(vec![(500, ""), (200, "not json")]);
+        let notifier = TelegramNotifier {
+            api_url: server.url.clone(),
+            bot_token: "123:secret".to_string(),
+        };
+
+        for _ in 0..2 {
+            let error = notifier.notify(&content(&["42"], &[])).unwrap_err();
+            assert!(!format!("{:#}", error).contains("secret"), "{:#}", error);
+        }
+    }
+}
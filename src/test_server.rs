@@ -0,0 +1,212 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+type Headers<'a> = Vec<(&'a str, &'a str)>;
+
+/// A minimal local HTTP server for testing notifiers against, which records the requests it
+/// receives and responds to each with a canned response.
+pub struct TestServer {
+    pub url: String,
+    requests: Receiver<RecordedRequest>,
+}
+
+impl TestServer {
+    /// Starts a server which responds to each request with the next of `responses`, given as
+    /// status code and body
+    pub fn start(responses: Vec<(u16, &str)>) -> Self {
+        Self::start_with_headers(
+            responses
+                .into_iter()
+                .map(|(status, body)| (status, vec![], body))
+                .collect(),
+        )
+    }
+
+    /// Like `start`, with extra headers for each response
+    pub fn start_with_headers(responses: Vec<(u16, Headers, &str)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let responses = responses
+            .into_iter()
+            .map(|(status, headers, body)| {
+                let headers = headers
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                    .collect::<String>();
+
+                (status, headers, body.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        let (sender, requests) = channel();
+
+        std::thread::spawn(move || {
+            for (status, response_headers, response_body) in responses {
+                let Ok((mut stream, _)) = listener.accept() else {
+                    return;
+                };
+
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let path = parts.next().unwrap_or_default().to_string();
+
+                let mut headers = vec![];
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+
+                    if line.is_empty() {
+                        break;
+                    }
+
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.push((name.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+
+                let content_length = headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, v)| v.parse::<usize>().unwrap());
+
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nContent-Type: application/json\r\n{}Connection: close\r\n\r\n{}",
+                    status,
+                    response_body.len(),
+                    response_headers,
+                    response_body
+                )
+                .unwrap();
+
+                _ = sender.send(RecordedRequest {
+                    method,
+                    path,
+                    headers,
+                    body: String::from_utf8_lossy(&body).to_string(),
+                });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// The next request the server received
+    pub fn request(&self) -> RecordedRequest {
+        self.requests.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordedMail {
+    pub from: String,
+    pub to: Vec<String>,
+    pub data: String,
+}
+
+/// A minimal local SMTP server which accepts every mail and records it
+pub struct SmtpSink {
+    pub url: String,
+    mails: Receiver<RecordedMail>,
+}
+
+impl SmtpSink {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+
+        let (sender, mails) = channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    return;
+                };
+
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                write!(stream, "220 localhost test\r\n").unwrap();
+
+                let mut from = String::new();
+                let mut to = vec![];
+
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+
+                    let command = line.trim_end();
+                    let upper = command.to_ascii_uppercase();
+
+                    if let Some(address) = upper.strip_prefix("MAIL FROM:") {
+                        from = command[command.len() - address.len()..].to_string();
+                    } else if let Some(address) = upper.strip_prefix("RCPT TO:") {
+                        to.push(command[command.len() - address.len()..].to_string());
+                    } else if upper == "DATA" {
+                        write!(stream, "354 go ahead\r\n").unwrap();
+
+                        let mut data = String::new();
+                        loop {
+                            let mut line = String::new();
+                            reader.read_line(&mut line).unwrap();
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            data.push_str(&line);
+                        }
+
+                        _ = sender.send(RecordedMail {
+                            from: std::mem::take(&mut from),
+                            to: std::mem::take(&mut to),
+                            data,
+                        });
+                    } else if upper == "QUIT" {
+                        write!(stream, "221 bye\r\n").unwrap();
+                        break;
+                    }
+
+                    write!(stream, "250 ok\r\n").unwrap();
+                }
+            }
+        });
+
+        Self { url, mails }
+    }
+
+    /// The next mail the server received
+    pub fn mail(&self) -> RecordedMail {
+        self.mails.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+}
@@ -0,0 +1,258 @@
+use crate::ccrl_pgn::Pgn;
+use crate::ccrllive::CcrlLiveRoom;
+use crate::notify::NotifierKind;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone)]
+pub struct TrackedGame {
+    pub room: CcrlLiveRoom,
+    pub game: Pgn,
+    /// The notifications sent for this game by each backend, which are updated as the game
+    /// progresses
+    pub notification_ids: HashMap<NotifierKind, String>,
+}
+
+pub struct TrackerUpdate {
+    /// Games which have had moves played since the last poll, and are still in progress
+    pub progressed: Vec<TrackedGame>,
+    pub finished: Vec<TrackedGame>,
+}
+
+/// Follows the in-progress game in each room across polls so we can tell when it finishes.
+pub struct GameTracker {
+    games: HashMap<String, TrackedGame>,
+}
+
+impl GameTracker {
+    pub fn new() -> Self {
+        Self {
+            games: HashMap::new(),
+        }
+    }
+
+    /// Update the tracked games with the latest poll.
+    ///
+    /// A game is finished once its result is known, or once its room has moved on to a different
+    /// game. In the latter case the last version of the game we saw is returned, so its result
+    /// may still be in progress.
+    pub fn update(&mut self, current_games: &[(CcrlLiveRoom, Pgn)]) -> TrackerUpdate {
+        let mut progressed = vec![];
+        let mut finished = vec![];
+
+        for (room, game) in current_games {
+            let mut notification_ids = HashMap::new();
+
+            if let Some(previous) = self.games.remove(&room.code()) {
+                if previous.game != *game {
+                    finished.push(previous);
+                } else {
+                    let tracked = TrackedGame {
+                        room: room.clone(),
+                        game: game.clone(),
+                        notification_ids: previous.notification_ids,
+                    };
+
+                    if game.result.is_finished() {
+                        finished.push(tracked);
+                        continue;
+                    }
+
+                    if game.moves.len() != previous.game.moves.len() {
+                        progressed.push(tracked.clone());
+                    }
+
+                    notification_ids = tracked.notification_ids;
+                }
+            }
+
+            // Games which have already finished when we first see them were never tracked, so
+            // we don't report them.
+            if !game.result.is_finished() {
+                self.games.insert(
+                    room.code(),
+                    TrackedGame {
+                        room: room.clone(),
+                        game: game.clone(),
+                        notification_ids,
+                    },
+                );
+            }
+        }
+
+        TrackerUpdate {
+            progressed,
+            finished,
+        }
+    }
+
+    /// Stops following games in rooms which are no longer broadcast, returning them as finished.
+    /// Rooms which are still broadcast keep their game, even if it couldn't be fetched this time.
+    pub fn close_rooms(&mut self, rooms: &[CcrlLiveRoom]) -> Vec<TrackedGame> {
+        let open = rooms.iter().map(|room| room.code()).collect::<HashSet<_>>();
+
+        let mut closed = self
+            .games
+            .extract_if(|code, _| !open.contains(code))
+            .map(|(_, tracked)| tracked)
+            .collect::<Vec<_>>();
+        closed.sort_by_key(|tracked| tracked.room.code());
+
+        closed
+    }
+
+    /// Record a notification sent for a game, if it's still the one tracked in `room`
+    pub fn set_notification_id(
+        &mut self,
+        room: &CcrlLiveRoom,
+        game_id: u64,
+        kind: NotifierKind,
+        notification_id: String,
+    ) {
+        if let Some(tracked) = self.games.get_mut(&room.code()) {
+            if tracked.game.game_id(room) == game_id {
+                tracked.notification_ids.insert(kind, notification_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccrl_pgn;
+
+    fn game_with_moves(white: &str, black: &str, result: &str, moves: &str) -> Pgn {
+        ccrl_pgn::get_pgn_info(&format!(
+            r#"[Site "Test Tournament"]
+[Date "2025.01.06"]
+[White "{white}"]
+[Black "{black}"]
+[Result "{result}"]
+
+1. d4 {{(Book)}} Nf6 {{(Nc3 d5) -0.41/18 1736170414}}{moves}"#
+        ))
+        .unwrap()
+    }
+
+    fn game(white: &str, black: &str, result: &str) -> Pgn {
+        game_with_moves(white, black, result, "")
+    }
+
+    #[test]
+    fn test_finished_result_is_reported_once() {
+        let room = CcrlLiveRoom::new("1");
+        let mut tracker = GameTracker::new();
+
+        let update = tracker.update(&[(room.clone(), game("A", "B", "*"))]);
+        assert!(update.finished.is_empty());
+
+        let update = tracker.update(&[(room.clone(), game("A", "B", "1-0"))]);
+        assert_eq!(update.finished.len(), 1);
+        assert!(update.finished[0].game.result.is_finished());
+
+        let update = tracker.update(&[(room.clone(), game("A", "B", "1-0"))]);
+        assert!(update.finished.is_empty());
+    }
+
+    #[test]
+    fn test_room_moving_on_finishes_previous_game() {
+        let room = CcrlLiveRoom::new("1");
+        let mut tracker = GameTracker::new();
+
+        tracker.update(&[(room.clone(), game("A", "B", "*"))]);
+
+        let update = tracker.update(&[(room.clone(), game("C", "D", "*"))]);
+        assert_eq!(update.finished.len(), 1);
+        assert!(update.finished[0].game.white_player.matches("A"));
+        assert!(!update.finished[0].game.result.is_finished());
+    }
+
+    #[test]
+    fn test_game_already_finished_when_first_seen_is_not_reported() {
+        let room = CcrlLiveRoom::new("1");
+        let mut tracker = GameTracker::new();
+
+        let update = tracker.update(&[(room.clone(), game("A", "B", "1/2-1/2"))]);
+        assert!(update.finished.is_empty());
+
+        let update = tracker.update(&[(room.clone(), game("C", "D", "*"))]);
+        assert!(update.finished.is_empty());
+    }
+
+    #[test]
+    fn test_notification_id_for_previous_game_is_ignored() {
+        let room = CcrlLiveRoom::new("1");
+        let mut tracker = GameTracker::new();
+
+        let previous = game("A", "B", "*");
+        tracker.update(&[(room.clone(), previous.clone())]);
+        tracker.update(&[(room.clone(), game("C", "D", "*"))]);
+
+        tracker.set_notification_id(
+            &room,
+            previous.game_id(&room),
+            NotifierKind::Discord,
+            "123".to_string(),
+        );
+
+        let update = tracker.update(&[(room.clone(), game("E", "F", "*"))]);
+        assert!(update.finished[0].notification_ids.is_empty());
+    }
+
+    #[test]
+    fn test_progress_is_reported_with_notification_ids() {
+        let room = CcrlLiveRoom::new("1");
+        let mut tracker = GameTracker::new();
+
+        let started = game("A", "B", "*");
+        tracker.update(&[(room.clone(), started.clone())]);
+        tracker.set_notification_id(
+            &room,
+            started.game_id(&room),
+            NotifierKind::Discord,
+            "123".to_string(),
+        );
+
+        let update = tracker.update(&[(room.clone(), game("A", "B", "*"))]);
+        assert!(update.progressed.is_empty());
+
+        let moved = game_with_moves("A", "B", "*", " 2. c4 {(g6) 0.20/18 10}");
+        let update = tracker.update(&[(room.clone(), moved.clone())]);
+        assert_eq!(update.progressed.len(), 1);
+        assert_eq!(
+            update.progressed[0].notification_ids[&NotifierKind::Discord],
+            "123"
+        );
+
+        let update = tracker.update(&[(room.clone(), game("C", "D", "*"))]);
+        assert_eq!(
+            update.finished[0].notification_ids[&NotifierKind::Discord],
+            "123"
+        );
+    }
+
+    #[test]
+    fn test_closed_room_finishes_its_game() {
+        let (first, second) = (CcrlLiveRoom::new("1"), CcrlLiveRoom::new("2"));
+        let mut tracker = GameTracker::new();
+
+        tracker.update(&[
+            (first.clone(), game("A", "B", "*")),
+            (second.clone(), game("C", "D", "*")),
+        ]);
+
+        // The second room's game couldn't be fetched, but it's still being broadcast
+        tracker.update(&[(first.clone(), game("A", "B", "*"))]);
+        let closed = tracker.close_rooms(&[first.clone(), second.clone()]);
+        assert!(closed.is_empty());
+
+        let closed = tracker.close_rooms(std::slice::from_ref(&second));
+        assert_eq!(closed.len(), 1);
+        assert!(closed[0].game.white_player.matches("A"));
+
+        assert!(tracker
+            .close_rooms(std::slice::from_ref(&second))
+            .is_empty());
+        assert_eq!(tracker.close_rooms(&[]).len(), 1);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::ccrllive::EngineName;
+use crate::config::{ConfigFile, ConfigSource, UserNotifier};
+use crate::webhook;
+use anyhow::Result;
+use lettre::message::Mailbox;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+
+    fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+fn parse_error(e: serde_json5::Error) -> Diagnostic {
+    let serde_json5::Error::Message { msg, location } = e;
+
+    match location {
+        Some(l) => Diagnostic::error(format!("line {}, column {}: {}", l.line, l.column, msg)),
+        None => Diagnostic::error(msg),
+    }
+}
+
+/// Checks a config file, returning any problems found with it.
+pub fn validate_config(contents: &str) -> Vec<Diagnostic> {
+    let mut deserializer = match serde_json5::Deserializer::from_str(contents) {
+        Ok(deserializer) => deserializer,
+        Err(e) => return vec![parse_error(e)],
+    };
+
+    let mut unknown_keys = vec![];
+
+    let config_file: Result<ConfigFile, _> =
+        serde_ignored::deserialize(&mut deserializer, |path| {
+            unknown_keys.push(path.to_string());
+        });
+
+    let config_file = match config_file {
+        Ok(config_file) => config_file,
+        Err(e) => return vec![parse_error(e)],
+    };
+
+    let mut diagnostics = unknown_keys
+        .into_iter()
+        .map(|key| Diagnostic::warning(format!("unknown key `{}`", key)))
+        .collect::<Vec<_>>();
+
+    let mut users = config_file.users.iter().collect::<Vec<_>>();
+    users.sort_by_key(|(user, _)| *user);
+
+    for (user, user_config) in &users {
+        for (i, engine) in user_config.engines.iter().enumerate() {
+            let earlier = user_config.engines[..i]
+                .iter()
+                .find(|other| EngineName::new(other) == EngineName::new(engine));
+
+            match earlier {
+                Some(other) if other == engine => diagnostics.push(Diagnostic::warning(format!(
+                    "users.{}.engines: `{}` is listed more than once",
+                    user, engine
+                ))),
+                Some(other) => diagnostics.push(Diagnostic::warning(format!(
+                    "users.{}.engines: `{}` and `{}` refer to the same engine",
+                    user, other, engine
+                ))),
+                None => {}
+            }
+        }
+
+        for (i, rule) in user_config.rules.iter().enumerate() {
+            let shadowed_by = user_config.rules[..i]
+                .iter()
+                .position(|earlier| earlier.shadows(rule));
+
+            if let Some(j) = shadowed_by {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "users.{}.rules.{}: unreachable, since rules.{} matches every game it would",
+                    user, i, j
+                )));
+            }
+        }
+    }
+
+    for (user, user_config) in &users {
+        for (i, notifier) in user_config.notifiers.iter().enumerate() {
+            match notifier {
+                UserNotifier::Webhook(target) => {
+                    match webhook::unknown_placeholders(&target.template) {
+                        Ok(unknown) => {
+                            for placeholder in unknown {
+                                diagnostics.push(Diagnostic::error(format!(
+                                    "users.{}.notifiers.{}.template: unknown placeholder `{}`",
+                                    user, i, placeholder
+                                )));
+                            }
+                        }
+                        Err(e) => diagnostics.push(Diagnostic::error(format!(
+                            "users.{}.notifiers.{}.template: {}",
+                            user, i, e
+                        ))),
+                    }
+                }
+                UserNotifier::Email { address, .. } => {
+                    if let Err(e) = address.parse::<Mailbox>() {
+                        diagnostics.push(Diagnostic::error(format!(
+                            "users.{}.notifiers.{}.address: invalid email address `{}`: {}",
+                            user, i, address, e
+                        )));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Different users may follow the same engine, but if they've spelled it differently one of
+    // them probably isn't getting the notifications they expect.
+    let engines = users
+        .iter()
+        .flat_map(|(user, user_config)| user_config.engines.iter().map(move |e| (*user, e)))
+        .collect::<Vec<_>>();
+
+    for (i, (user, engine)) in engines.iter().enumerate() {
+        let other = engines[..i].iter().find(|(other_user, other_engine)| {
+            other_user != user
+                && other_engine != engine
+                && EngineName::new(other_engine) == EngineName::new(engine)
+        });
+
+        if let Some((other_user, other_engine)) = other {
+            diagnostics.push(Diagnostic::warning(format!(
+                "users.{}.engines: `{}` refers to the same engine as `{}` in users.{}",
+                user, engine, other_engine, other_user
+            )));
+        }
+    }
+
+    diagnostics
+}
+
+/// Validates the config at `location`, printing any problems. Returns whether the config is valid.
+pub fn validate_config_source(location: &str) -> Result<bool> {
+    let contents = ConfigSource::parse(location)?.read()?;
+
+    let diagnostics = validate_config(&contents);
+
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+
+    println!("{}: {} errors, {} warnings", location, errors, warnings);
+
+    Ok(errors == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(contents: &str) -> Vec<String> {
+        validate_config(contents)
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_config_has_no_diagnostics() {
+        let diagnostics = validate_config(
+            r#"{
+  users: {
+    "1": { engines: ["Lunar"], rules: [{ pattern: "Amateur", action: "ignore" }] },
+    "2": { engines: ["Lunar", "Luna"] },
+  }
+}"#,
+        );
+
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_syntax_error_has_location() {
+        let messages = messages("{\n  users: {\n    \"1\": { engines: [\"Lunar\" }\n  }\n}");
+
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("error: line 3, column"));
+    }
+
+    #[test]
+    fn test_bad_regex_has_location() {
+        let messages = messages(
+            r#"{
+  users: {
+    "1": { engines: ["Lunar"], rules: [{ pattern: "(", action: "ignore" }] },
+  }
+}"#,
+        );
+
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("error: line 3, column"));
+    }
+
+    #[test]
+    fn test_unknown_keys_are_warned() {
+        let messages = messages(r#"{ users: { "1": { engines: ["Lunar"], enignes: ["Luna"] } } }"#);
+
+        assert_eq!(messages, vec!["warning: unknown key `users.1.enignes`"]);
+    }
+
+    #[test]
+    fn test_duplicate_engines_are_warned() {
+        let messages =
+            messages(r#"{ users: { "1": { engines: ["Lunar", "Lunar", "lunar 2"] } } }"#);
+
+        assert_eq!(
+            messages,
+            vec![
+                "warning: users.1.engines: `Lunar` is listed more than once",
+                "warning: users.1.engines: `Lunar` and `lunar 2` refer to the same engine",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_engines_normalizing_to_the_same_name_across_users_are_warned() {
+        let messages = messages(
+            r#"{ users: { "1": { engines: ["Colossus"] }, "2": { engines: ["Colossus 2025b"] } } }"#,
+        );
+
+        assert_eq!(
+            messages,
+            vec!["warning: users.2.engines: `Colossus 2025b` refers to the same engine as `Colossus` in users.1"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_email_addresses_are_errors() {
+        let messages = messages(
+            r#"{
+  users: {
+    "1": {
+      engines: ["Lunar"],
+      notifiers: [
+        { type: "email", address: "Bob <bob@example.com>" },
+        { type: "email", address: "bob.example.com", digest: true },
+      ],
+    },
+  }
+}"#,
+        );
+
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with(
+            "error: users.1.notifiers.1.address: invalid email address `bob.example.com`"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_webhook_placeholders_are_errors() {
+        let messages = messages(
+            r#"{
+  users: {
+    "1": {
+      engines: ["Lunar"],
+      notifiers: [{ type: "webhook", url: "https://example.com", template: "{{white}} {{whte}}" }],
+    },
+  }
+}"#,
+        );
+
+        assert_eq!(
+            messages,
+            vec!["error: users.1.notifiers.0.template: unknown placeholder `whte`"]
+        );
+    }
+
+    #[test]
+    fn test_shadowed_rules_are_warned() {
+        let messages = messages(
+            r#"{
+  users: {
+    "1": {
+      engines: ["Lunar"],
+      rules: [
+        { pattern: "Amateur", action: "ignore" },
+        { pattern: "Amateur", colour: "white", action: "notify" },
+        { opponent: "Stockfish", action: "notify" },
+      ]
+    },
+  }
+}"#,
+        );
+
+        assert_eq!(
+            messages,
+            vec![
+                "warning: users.1.rules.1: unreachable, since rules.0 matches every game it would"
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::config::redact_url;
+use crate::notify::{send_to_each, send_to_secret_url, Notifier, NotifyContent};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// The placeholders which can be used in templates
+const PLACEHOLDERS: &[&str] = &[
+    "event",
+    "white",
+    "black",
+    "tournament",
+    "room",
+    "room_url",
+    "eval",
+    "result",
+    "termination",
+    "plies",
+];
+
+/// Where a user's webhook is sent, and what's sent to it
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct WebhookTarget {
+    pub url: String,
+    /// The request body, with `{{placeholder}}`s replaced with details of the game. Values are
+    /// escaped for JSON if the content type is JSON, unless written as `{{{placeholder}}}`.
+    pub template: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+// Webhook URLs often carry a secret in their path or query, so only the site is shown
+impl std::fmt::Debug for WebhookTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookTarget")
+            .field("url", &redact_url(&self.url))
+            .field("template", &self.template)
+            .field("content_type", &self.content_type)
+            .finish()
+    }
+}
+
+impl WebhookTarget {
+    fn is_json(&self) -> bool {
+        self.content_type.starts_with("application/json")
+    }
+}
+
+/// Splits a template into literal text and placeholders, returning each placeholder's name and
+/// whether it's raw (written with triple braces)
+fn parse_template(template: &str) -> Result<Vec<(&str, Option<bool>)>> {
+    let mut parts = vec![];
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        parts.push((&rest[..start], None));
+        rest = &rest[start..];
+
+        let (raw, open, close) = if rest.starts_with("{{{") {
+            (true, "{{{", "}}}")
+        } else {
+            (false, "{{", "}}")
+        };
+
+        let Some(end) = rest.find(close) else {
+            bail!("Unclosed placeholder in template: {}", rest);
+        };
+
+        parts.push((rest[open.len()..end].trim(), Some(raw)));
+        rest = &rest[end + close.len()..];
+    }
+
+    parts.push((rest, None));
+
+    Ok(parts)
+}
+
+/// Returns the placeholders in `template` which aren't known
+pub fn unknown_placeholders(template: &str) -> Result<Vec<String>> {
+    Ok(parse_template(template)?
+        .into_iter()
+        .filter(|(name, raw)| raw.is_some() && !PLACEHOLDERS.contains(name))
+        .map(|(name, _)| name.to_string())
+        .collect())
+}
+
+fn placeholder_value(name: &str, event: &str, content: &NotifyContent) -> Result<String> {
+    let value = match name {
+        "event" => event.to_string(),
+        "white" => content.white_player.to_string(),
+        "black" => content.black_player.to_string(),
+        "tournament" => content.tournament.clone(),
+        "room" => content.room.code(),
+        "room_url" => content.room.url(),
+        "eval" => content.score.map(|s| s.to_string()).unwrap_or_default(),
+        "result" => content.result.to_string(),
+        "termination" => content.termination.clone().unwrap_or_default(),
+        "plies" => content.plies.to_string(),
+        _ => bail!("Unknown placeholder in template: {}", name),
+    };
+
+    Ok(value)
+}
+
+fn render(target: &WebhookTarget, event: &str, content: &NotifyContent) -> Result<String> {
+    let mut rendered = String::new();
+
+    for (text, placeholder) in parse_template(&target.template)? {
+        let Some(raw) = placeholder else {
+            rendered.push_str(text);
+            continue;
+        };
+
+        let value = placeholder_value(text, event, content)?;
+
+        if target.is_json() && !raw {
+            // Escape the value as a JSON string, without the surrounding quotes
+            let escaped = serde_json::to_string(&value)?;
+            rendered.push_str(&escaped[1..escaped.len() - 1]);
+        } else {
+            rendered.push_str(&value);
+        }
+    }
+
+    Ok(rendered)
+}
+
+/// Sends user-defined requests to any URL, for integrating with tools we don't have a backend for
+pub struct WebhookNotifier;
+
+impl WebhookNotifier {
+    fn send(event: &str, content: &NotifyContent) -> Result<()> {
+        let client = reqwest::blocking::Client::new();
+
+        send_to_each(content, |recipient| {
+            let target = recipient.webhook_target()?;
+
+            send_to_secret_url(
+                client
+                    .post(&target.url)
+                    .header(reqwest::header::CONTENT_TYPE, &target.content_type)
+                    .body(render(target, event, content)?),
+            )?;
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn send_message(&self, _message: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn notify(&self, content: &NotifyContent) -> Result<Option<String>> {
+        Self::send("started", content)?;
+
+        Ok(None)
+    }
+
+    fn notify_finished(
+        &self,
+        _notification_id: Option<&str>,
+        content: &NotifyContent,
+    ) -> Result<()> {
+        Self::send("finished", content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::content;
+    use crate::notify::Recipient;
+    use crate::test_server::TestServer;
+    use std::collections::HashSet;
+
+    fn target(template: &str, content_type: &str) -> WebhookTarget {
+        WebhookTarget {
+            url: "http://localhost".to_string(),
+            template: template.to_string(),
+            content_type: content_type.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_escapes_json() {
+        let mut content = content(&[], &[]);
+        content.tournament = "\"Quoted\" Cup".to_string();
+
+        let rendered = render(
+            &target(
+                r#"{ "text": "{{ white }} vs. {{black}} in {{tournament}}: {{result}} ({{eval}})" }"#,
+                "application/json",
+            ),
+            "finished",
+            &content,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rendered,
+            r#"{ "text": "RookieMonster 1.9.9 64-bit vs. Betsabe_II 2023 in \"Quoted\" Cup: 1-0 (-0.41)" }"#
+        );
+    }
+
+    #[test]
+    fn test_render_raw_and_text() {
+        let mut content = content(&[], &[]);
+        content.tournament = "\"Quoted\" Cup".to_string();
+
+        let rendered = render(
+            &target("{{{tournament}}} {{room_url}}", "application/json"),
+            "started",
+            &content,
+        )
+        .unwrap();
+        assert_eq!(rendered, "\"Quoted\" Cup https://ccrl.live/1");
+
+        let rendered = render(
+            &target("{{event}}: {{tournament}}", "text/plain"),
+            "started",
+            &content,
+        )
+        .unwrap();
+        assert_eq!(rendered, "started: \"Quoted\" Cup");
+    }
+
+    #[test]
+    fn test_unknown_placeholders() {
+        assert_eq!(
+            unknown_placeholders("{{white}} {{whtie}} {{{room}}}").unwrap(),
+            vec!["whtie"]
+        );
+        assert!(unknown_placeholders("{{white").is_err());
+    }
+
+    #[test]
+    fn test_posts_rendered_template() {
+        let server = TestServer::start(vec![(200, "")]);
+
+        let mut content = content(&[], &[]);
+        content.mentions = HashSet::from([Recipient::Webhook(WebhookTarget {
+            url: format!("{}/hook", server.url),
+            template: r#"{ "event": "{{event}}", "room": "{{room_url}}" }"#.to_string(),
+            content_type: default_content_type(),
+        })]);
+
+        WebhookNotifier.notify(&content).unwrap();
+
+        let request = server.request();
+        assert_eq!(request.path, "/hook");
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(
+            request.json(),
+            serde_json::json!({ "event": "started", "room": "https://ccrl.live/1" })
+        );
+    }
+
+    #[test]
+    fn test_errors_do_not_include_url() {
+        let server = TestServer::start(vec![(500, "")]);
+
+        let mut content = content(&[], &[]);
+        content.mentions = HashSet::from([Recipient::Webhook(WebhookTarget {
+            url: format!("{}/hook/secret", server.url),
+            template: "{{event}}".to_string(),
+            content_type: "text/plain".to_string(),
+        })]);
+
+        let error = WebhookNotifier.notify(&content).unwrap_err();
+        assert!(!format!("{:#}", error).contains("secret"), "{:#}", error);
+    }
+}